@@ -1,5 +1,286 @@
+use std::{collections::BTreeMap, fmt};
+
 use serde::{Deserialize, Serialize};
 
+use super::response::{BlockReason, SafetyRating};
+
+/// Errors raised by the client, returned wrapped in `anyhow::Error`.
+///
+/// Use `err.downcast_ref::<GeminiError>()` to inspect them.
+#[derive(Clone, Debug)]
+pub enum GeminiError {
+    /// The API returned an error response.
+    Api {
+        /// The HTTP status code.
+        code: i16,
+        /// The error message.
+        message: String,
+        /// The error status, e.g. `INVALID_ARGUMENT`.
+        status: Option<String>,
+        /// The typed error details.
+        details: Vec<ErrorDetail>,
+    },
+    /// The model still requested function calls after the maximum number of tool loop iterations.
+    ToolLoopExceeded(usize),
+    /// The first candidate of the response contains no text part.
+    NoTextPart,
+    /// The text of the response is not valid JSON for the requested type.
+    InvalidJson {
+        /// The text that failed to deserialize.
+        text: String,
+        /// The deserialization error message.
+        message: String,
+    },
+    /// The response body could not be parsed into a response, e.g. because the schema of the API changed.
+    InvalidResponse {
+        /// The raw response body.
+        raw: String,
+        /// The deserialization error message.
+        message: String,
+    },
+    /// The API key was rejected by the API.
+    InvalidKey(String),
+    /// The model is not in the allow list of the client.
+    ModelNotAllowed(String),
+    /// The prompt was blocked and no candidates were returned.
+    PromptBlocked {
+        /// The reason why the prompt was blocked.
+        block_reason: BlockReason,
+        /// Ratings for safety of the prompt, telling which category triggered the block.
+        safety_ratings: Vec<SafetyRating>,
+    },
+    /// The response body exceeded the configured maximum size in bytes.
+    ResponseTooLarge(usize),
+    /// The model kept replying with empty or whitespace-only text after the configured number of retries.
+    EmptyResponse(usize),
+    /// The cached content of the client is bound to another model than the one of the request.
+    CachedContentModelMismatch {
+        /// The model the cached content is bound to.
+        cached_model: String,
+        /// The model of the request.
+        model: String,
+    },
+    /// The streamed response was interrupted before it finished.
+    StreamInterrupted {
+        /// The text received before the interruption.
+        partial: String,
+        /// The error that interrupted the stream.
+        message: String,
+    },
+}
+
+impl fmt::Display for GeminiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeminiError::Api { message, .. } => write!(f, "{}", message),
+            GeminiError::ToolLoopExceeded(max_iterations) => {
+                write!(f, "Tool loop exceeded the maximum of {} iterations", max_iterations)
+            }
+            GeminiError::NoTextPart => write!(f, "No text part in the response"),
+            GeminiError::InvalidJson { message, .. } => write!(f, "Invalid JSON in the response: {}", message),
+            GeminiError::InvalidResponse { raw, message } => {
+                write!(f, "Invalid response body: {}, raw response: {}", message, raw)
+            }
+            GeminiError::InvalidKey(message) => write!(f, "Invalid API key: {}", message),
+            GeminiError::ModelNotAllowed(model) => write!(f, "Model {} is not allowed", model),
+            GeminiError::PromptBlocked { block_reason, .. } => write!(f, "Prompt was blocked: {:?}", block_reason),
+            GeminiError::ResponseTooLarge(limit) => write!(f, "Response exceeded the limit of {} bytes", limit),
+            GeminiError::EmptyResponse(retries) => write!(f, "Response was still empty after {} retries", retries),
+            GeminiError::CachedContentModelMismatch { cached_model, model } => write!(
+                f,
+                "Cached content is bound to model {}, but the request uses model {}",
+                cached_model, model
+            ),
+            GeminiError::StreamInterrupted { message, .. } => write!(f, "Stream interrupted: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for GeminiError {}
+
+impl GeminiError {
+    /// Returns whether the failed request is worth retrying as is.
+    ///
+    /// Rate limits (429), server errors (500, 502, 503), timeouts (408, 504) and interrupted streams are retryable,
+    /// other client errors (4xx) and local errors are not. Network errors are returned as `reqwest::Error` rather than
+    /// `GeminiError`, use `is_retryable_error` to classify any error returned by the client.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            GeminiError::Api { code, .. } => is_retryable_status(*code as u16),
+            GeminiError::StreamInterrupted { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns a short, friendly message describing the error, suitable to show to end users.
+    ///
+    /// Common failures (invalid API key, quota exceeded, model not found, blocked prompt, unavailable service) are
+    /// mapped to a fixed message, the technical detail is kept in `Display` and `Debug`.
+    pub fn user_message(&self) -> String {
+        let message = match self {
+            GeminiError::InvalidKey(_) => "The API key is invalid, please check your settings.",
+            GeminiError::Api { .. } if self.has_reason("API_KEY_INVALID") => {
+                "The API key is invalid, please check your settings."
+            }
+            GeminiError::Api { code: 401 | 403, .. } => "The API key is not allowed to use this service.",
+            GeminiError::Api { code: 429, .. } => "The usage quota has been exceeded, please try again later.",
+            GeminiError::Api { code: 404, .. } | GeminiError::ModelNotAllowed(_) => {
+                "The requested model is not available."
+            }
+            GeminiError::Api {
+                code: 408 | 500..=599, ..
+            }
+            | GeminiError::StreamInterrupted { .. } => {
+                "The service is temporarily unavailable, please try again later."
+            }
+            GeminiError::Api { code: 400, .. } => "The request is invalid.",
+            GeminiError::PromptBlocked { .. } => "The message was blocked for safety reasons.",
+            GeminiError::ResponseTooLarge(_) => "The response is too large.",
+            _ => "Something went wrong, please try again.",
+        };
+        message.to_owned()
+    }
+
+    /// Returns whether the API error was caused by an invalid or unauthorized API key.
+    pub(crate) fn is_key_error(&self) -> bool {
+        matches!(self, GeminiError::Api { code: 401 | 403, .. }) || self.has_reason("API_KEY_INVALID")
+    }
+
+    /// Returns whether the details of an API error contain the given reason, e.g. `API_KEY_INVALID`.
+    fn has_reason(&self, reason: &str) -> bool {
+        let GeminiError::Api { details, .. } = self else {
+            return false;
+        };
+        details
+            .iter()
+            .any(|detail| matches!(detail, ErrorDetail::ErrorInfo { reason: Some(r), .. } if r == reason))
+    }
+
+    /// Builds `GeminiError::Api` from the HTTP status code and the body of a failed response.
+    ///
+    /// Bodies that are not the JSON error of the API, e.g. the HTML error page of a proxy, keep the status code and
+    /// use the body as the message.
+    pub fn from_status_and_text(code: u16, response_text: &str) -> Self {
+        Self::from_response_text(response_text).unwrap_or_else(|_| GeminiError::Api {
+            code: code as i16,
+            message: response_text.trim().to_owned(),
+            status: None,
+            details: Vec::new(),
+        })
+    }
+
+    /// Parses the error response body returned by the API into `GeminiError::Api`.
+    pub fn from_response_text(response_text: &str) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct ErrorBody {
+            error: ErrorInner,
+        }
+        #[derive(Deserialize)]
+        struct ErrorInner {
+            code: i16,
+            message: String,
+            status: Option<String>,
+            #[serde(default)]
+            details: Vec<ErrorDetail>,
+        }
+        let ErrorInner {
+            code,
+            message,
+            status,
+            details,
+        } = serde_json::from_str::<ErrorBody>(response_text)?.error;
+        Ok(GeminiError::Api {
+            code,
+            message,
+            status,
+            details,
+        })
+    }
+}
+
+/// Returns whether a request that failed with the given error is worth retrying as is.
+///
+/// `GeminiError`s are classified by `GeminiError::is_retryable`. Network errors returned as `reqwest::Error` are
+/// retryable if the request timed out, the connection failed or the body could not be received.
+pub fn is_retryable_error(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<GeminiError>() {
+        return error.is_retryable();
+    }
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(error) => error.is_timeout() || error.is_connect() || error.is_request() || error.is_body(),
+        None => false,
+    }
+}
+
+/// Returns whether a request that failed with the given HTTP status code is worth retrying as is.
+///
+/// Rate limits (429), server errors (500, 502, 503) and timeouts (408, 504) are retryable.
+pub(crate) fn is_retryable_status(code: u16) -> bool {
+    matches!(code, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// Typed representation of the common error details returned by the API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "@type")]
+pub enum ErrorDetail {
+    /// Describes the cause of the error with structured details.
+    #[serde(rename = "type.googleapis.com/google.rpc.ErrorInfo")]
+    ErrorInfo {
+        /// The reason of the error, e.g. `API_KEY_INVALID`.
+        reason: Option<String>,
+        /// The logical grouping to which the reason belongs.
+        domain: Option<String>,
+        /// Additional structured details about this error.
+        metadata: Option<BTreeMap<String, String>>,
+    },
+    /// Describes how a quota check failed.
+    #[serde(rename = "type.googleapis.com/google.rpc.QuotaFailure")]
+    QuotaFailure {
+        /// Describes all quota violations.
+        #[serde(default)]
+        violations: Vec<QuotaViolation>,
+    },
+    /// Describes when the clients can retry a failed request.
+    #[serde(rename = "type.googleapis.com/google.rpc.RetryInfo", rename_all = "camelCase")]
+    RetryInfo {
+        /// Clients should wait at least this long between retrying the same request, e.g. `"30s"`.
+        retry_delay: Option<String>,
+    },
+    /// Describes violations in a client request.
+    #[serde(rename = "type.googleapis.com/google.rpc.BadRequest", rename_all = "camelCase")]
+    BadRequest {
+        /// Describes all violations in a client request.
+        #[serde(default)]
+        field_violations: Vec<FieldViolation>,
+    },
+    /// Any other detail type.
+    #[serde(other)]
+    Other,
+}
+
+/// A single quota violation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaViolation {
+    /// The subject on which the quota check failed.
+    pub subject: Option<String>,
+    /// A description of how the quota check failed.
+    pub description: Option<String>,
+    /// The metric of the violated quota.
+    pub quota_metric: Option<String>,
+    /// The id of the violated quota.
+    pub quota_id: Option<String>,
+}
+
+/// A single bad request field.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FieldViolation {
+    /// A path that leads to a field in the request body.
+    pub field: Option<String>,
+    /// A description of why the request element is bad.
+    pub description: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GenerateContentResponseError {
     pub error: Error,
@@ -2,17 +2,24 @@ pub mod error;
 pub mod request;
 pub mod response;
 
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
 
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
 
 /// The base structured datatype containing multi-part content of a message.
 ///
 /// A Content includes a role field designating the producer of the Content and a parts field containing multi-part data
 /// that contains the content of the message turn.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Content {
     /// Ordered Parts that constitute a single message. Parts may have different MIME types.
+    #[serde(with = "parts", default)]
     pub parts: Vec<Part>,
     /// Optional. The producer of the content. Must be either 'user' or 'model'.
     /// Useful to set for multi-turn conversations, otherwise can be left blank or unset.
@@ -20,12 +27,90 @@ pub struct Content {
     pub role: Option<Role>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl Content {
+    /// Returns the concatenated text of all the text parts.
+    pub fn text(&self) -> String {
+        self.text_joined("")
+    }
+
+    /// Returns the text of all the text parts joined with `separator`.
+    pub fn text_joined(&self, separator: &str) -> String {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    /// Returns the role and the concatenated text of a text-only content.
+    ///
+    /// Returns `None` if the role is unset or any part is not text.
+    pub fn as_role_text(&self) -> Option<(Role, String)> {
+        let role = self.role.clone()?;
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(s) => text.push_str(s),
+                _ => return None,
+            }
+        }
+        Some((role, text))
+    }
+
+    /// Removes consecutive identical parts, keeping the first of each run.
+    pub fn dedup_parts(&mut self) {
+        self.parts.dedup();
+    }
+}
+
+/// Builds a single text part content from a (role, text) tuple.
+impl From<(Role, String)> for Content {
+    fn from((role, text): (Role, String)) -> Self {
+        Self {
+            parts: vec![Part::Text(text)],
+            role: Some(role),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Role {
     #[serde(rename = "user")]
     User,
     #[serde(rename = "model")]
     Model,
+    #[serde(rename = "system")]
+    System,
+    #[serde(rename = "function")]
+    Function,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => write!(f, "user"),
+            Role::Model => write!(f, "model"),
+            Role::System => write!(f, "system"),
+            Role::Function => write!(f, "function"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "model" => Ok(Role::Model),
+            "system" => Ok(Role::System),
+            "function" => Ok(Role::Function),
+            _ => bail!("Unknown role: {}", s),
+        }
+    }
 }
 
 /// A datatype containing media that is part of a multi-part Content message.
@@ -33,7 +118,7 @@ pub enum Role {
 /// A Part consists of data which has an associated datatype. A Part can only contain one of the accepted types in
 /// Part.data. A Part must have a fixed IANA MIME type identifying the type and subtype of the media if the inlineData
 /// field is filled with raw bytes.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Part {
     /// Inline text.
     #[serde(rename = "text")]
@@ -49,27 +134,25 @@ pub enum Part {
         /// Raw bytes for media formats.
         /// A base64-encoded string.
         data: String,
+        /// Optional. Metadata for a video, only used when the media is a video.
+        #[serde(skip_serializing_if = "Option::is_none", rename = "videoMetadata")]
+        video_metadata: Option<VideoMetadata>,
     },
-    /// A predicted FunctionCall returned from the model that contains a string representing the
-    /// FunctionDeclaration.name with the arguments and their values.
-    FunctionCall {
-        /// Required. The name of the function to call. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with
-        /// a maximum length of 63.
-        name: String,
-        /// Optional. The function parameters and values in JSON object format.
-        #[serde(skip_serializing_if = "Option::is_none")]
-        args: Option<BTreeMap<String, serde_json::Value>>,
-    },
-    /// The result output of a FunctionCall that contains a string representing the FunctionDeclaration.name and a
-    /// structured JSON object containing any output from the function is used as context to the model.
-    FunctionResponse {
-        /// Required. The name of the function to call. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with
-        /// a maximum length of 63.
-        name: String,
-        /// Required. The function response in JSON object format.
-        response: BTreeMap<String, serde_json::Value>,
-    },
+    /// An image referenced by URL, downloaded and sent as inline data when the request is built.
+    ///
+    /// A download error fails the request. In a conversation the part is replaced in the history once downloaded, so
+    /// the image is not downloaded again. Serialized as `imageUrl` so contents holding it can be saved, this part is
+    /// never sent to the API as is and requires the `image_analysis` feature to be sent.
+    #[serde(rename = "imageUrl")]
+    ImageUrl(String),
+    /// A predicted FunctionCall returned from the model.
+    #[serde(rename = "functionCall")]
+    FunctionCall(FunctionCall),
+    /// The result output of a FunctionCall.
+    #[serde(rename = "functionResponse")]
+    FunctionResponse(FunctionResponse),
     /// URI based data.
+    #[serde(rename = "fileData")]
     FileData {
         /// Optional. The IANA standard MIME type of the source data.
         #[serde(skip_serializing_if = "Option::is_none", rename = "mimeType")]
@@ -77,8 +160,12 @@ pub enum Part {
         /// Required. URI.
         #[serde(rename = "fileUri")]
         file_uri: String,
+        /// Optional. Metadata for a video, only used when the file is a video.
+        #[serde(skip_serializing_if = "Option::is_none", rename = "videoMetadata")]
+        video_metadata: Option<VideoMetadata>,
     },
     /// Code generated by the model that is meant to be executed.
+    #[serde(rename = "executableCode")]
     ExecutableCode {
         /// Required. Programming language of the code.
         language: Language,
@@ -86,6 +173,7 @@ pub enum Part {
         code: String,
     },
     /// Result of executing the ExecutableCode.
+    #[serde(rename = "codeExecutionResult")]
     CodeExecutionResult {
         /// Required. Outcome of the code execution.
         outcome: Outcome,
@@ -95,8 +183,157 @@ pub enum Part {
     },
 }
 
+impl Part {
+    /// Builds an image part from the URL of a remote image, which is downloaded only when the request is sent.
+    #[cfg(feature = "image_analysis")]
+    pub fn image_url(url: impl Into<String>) -> Self {
+        Self::ImageUrl(url.into())
+    }
+
+    /// Decodes the data of an inline data part and writes it to the given path.
+    ///
+    /// The data is decoded as standard base64, falling back to URL-safe base64 as returned by some proxies.
+    #[cfg(feature = "image_analysis")]
+    pub fn save_inline_data(&self, path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
+        let Self::InlineData { data, .. } = self else {
+            bail!("Part is not inline data")
+        };
+        std::fs::write(path, crate::utils::image::decode_base64(data)?)?;
+        Ok(())
+    }
+}
+
+/// A predicted FunctionCall returned from the model that contains a string representing the FunctionDeclaration.name
+/// with the arguments and their values.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FunctionCall {
+    /// Required. The name of the function to call. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a
+    /// maximum length of 63.
+    pub name: String,
+    /// Optional. The function parameters and values in JSON object format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<serde_json::Value>,
+    /// Optional. An opaque signature of the model thoughts, sent as the `thoughtSignature` field of the part. It must
+    /// be sent back unchanged in the history for thinking models to continue the function calling.
+    #[serde(skip)]
+    pub thought_signature: Option<String>,
+}
+
+/// (De)serializes the parts of a content, keeping the `thoughtSignature` field that the API puts beside the data of a
+/// part.
+///
+/// The signature of a function call part is kept in `FunctionCall::thought_signature`, the signatures of the other
+/// parts are dropped.
+mod parts {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    use super::Part;
+
+    const THOUGHT_SIGNATURE: &str = "thoughtSignature";
+
+    /// A part with its signature beside the data of the part.
+    #[derive(Serialize)]
+    struct SignedPart<'a> {
+        #[serde(flatten)]
+        part: &'a Part,
+        #[serde(rename = "thoughtSignature", skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<&'a str>,
+    }
+
+    pub fn serialize<S: Serializer>(parts: &[Part], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(parts.iter().map(|part| SignedPart {
+            part,
+            thought_signature: match part {
+                Part::FunctionCall(call) => call.thought_signature.as_deref(),
+                _ => None,
+            },
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Part>, D::Error> {
+        Vec::<Value>::deserialize(deserializer)?
+            .into_iter()
+            .map(|mut value| {
+                let signature = value.as_object_mut().and_then(|map| map.remove(THOUGHT_SIGNATURE));
+                let mut part: Part = serde_json::from_value(value).map_err(de::Error::custom)?;
+                if let (Part::FunctionCall(call), Some(Value::String(signature))) = (&mut part, signature) {
+                    call.thought_signature = Some(signature);
+                }
+                Ok(part)
+            })
+            .collect()
+    }
+}
+
+/// The result output of a FunctionCall that contains a string representing the FunctionDeclaration.name and a
+/// structured JSON object containing any output from the function is used as context to the model.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FunctionResponse {
+    /// Required. The name of the function to call. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a
+    /// maximum length of 63.
+    pub name: String,
+    /// Required. The function response in JSON object format.
+    pub response: BTreeMap<String, serde_json::Value>,
+}
+
+impl FunctionResponse {
+    /// Builds the response of a function call from the value returned by the function.
+    ///
+    /// JSON objects are used as is, any other value is wrapped as `{"result": value}`.
+    pub fn new(name: String, value: serde_json::Value) -> Self {
+        let response = match value {
+            serde_json::Value::Object(map) => map.into_iter().collect(),
+            value => BTreeMap::from([("result".to_owned(), value)]),
+        };
+        Self { name, response }
+    }
+}
+
+/// Metadata describes the input video content.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoMetadata {
+    /// Optional. The start offset of the video. A duration in seconds with up to nine fractional digits, ending with
+    /// 's'. Example: "3.5s".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_offset: Option<String>,
+    /// Optional. The end offset of the video. A duration in seconds with up to nine fractional digits, ending with
+    /// 's'. Example: "3.5s".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_offset: Option<String>,
+    /// Optional. The frame rate of the video sent to the model. If not specified, the default value will be 1.0. The
+    /// fps range is (0.0, 24.0].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps: Option<f64>,
+}
+
+/// Compares the canonical serialized form, see [`canonical`].
+impl PartialEq for VideoMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        canonical(self) == canonical(other)
+    }
+}
+
+impl Eq for VideoMetadata {}
+
+/// Hashes the canonical serialized form, consistently with `PartialEq`.
+impl Hash for VideoMetadata {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        canonical(self).hash(state)
+    }
+}
+
+/// Returns the canonical serialized form of a type containing floats, used to implement `Eq` and `Hash` for it.
+///
+/// Floats are neither `Eq` nor `Hash`, so these types are compared and hashed through their JSON value instead: two
+/// values are equal if they serialize to the same request, and `NaN`, which serializes to `null`, equals itself.
+pub(crate) fn canonical(value: &impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
 /// Supported programming languages for the generated code.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Language {
     /// Unspecified language. This value should not be used.
     #[serde(rename = "LANGUAGE_UNSPECIFIED")]
@@ -107,7 +344,7 @@ pub enum Language {
 }
 
 /// Enumeration of possible outcomes of the code execution.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Outcome {
     /// Unspecified status. This value should not be used.
     #[serde(rename = "OUTCOME_UNSPECIFIED")]
@@ -1,13 +1,21 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
 
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
-use super::Content;
+use super::{canonical, Content};
 
 /// The request body contains data with the following structure
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct GeminiRequestBody {
+    /// Optional. The name of the Model to use for generating the completion. Format: models/{model}.
+    /// Only required when the request is nested in another request, e.g. countTokens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
     /// Required. The content of the current conversation with the model.
     /// For single-turn queries, this is a single instance.
     /// For multi-turn queries like chat, this is a repeated field that contains the conversation history and the
@@ -48,6 +56,213 @@ pub struct GeminiRequestBody {
     pub cached_content: Option<String>,
 }
 
+impl GeminiRequestBody {
+    /// Returns a stable fingerprint of the request, for caching and deduplicating identical requests.
+    ///
+    /// The request is serialized canonically with sorted object keys and hashed with 64-bit FNV-1a, so the
+    /// fingerprint is the same across runs and platforms.
+    pub fn fingerprint(&self) -> String {
+        // Converting to a `Value` first sorts the object keys
+        let canonical = serde_json::to_value(self)
+            .expect("request body is always serializable")
+            .to_string();
+        let hash = canonical.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{:016x}", hash)
+    }
+}
+
+/// Request to generate a grounded answer from the Model.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateAnswerRequest {
+    /// Required. The content of the current conversation with the Model. For single-turn queries, this is a single
+    /// question to answer. For multi-turn queries, this is a repeated field that contains conversation history and
+    /// the last Content in the list containing the question.
+    /// Note: GenerateAnswer only supports queries in English.
+    pub contents: Vec<Content>,
+    /// Required. Style in which answers should be returned.
+    pub answer_style: AnswerStyle,
+    /// Passages provided inline with the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inline_passages: Option<GroundingPassages>,
+    /// Content retrieved from resources created via the Semantic Retriever API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub semantic_retriever: Option<SemanticRetrieverConfig>,
+    /// Optional. A list of unique SafetySetting instances for blocking unsafe content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety_settings: Option<Vec<SafetySetting>>,
+    /// Optional. Controls the randomness of the output.
+    /// Values can range from [0.0,1.0], inclusive. A value closer to 1.0 will produce responses that are more varied
+    /// and creative, while a value closer to 0.0 will typically result in more straightforward responses from the
+    /// model. A low temperature (~0.2) is usually recommended for Attributed-Question-Answering use cases.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+}
+
+/// Style for grounded answers.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum AnswerStyle {
+    /// Unspecified answer style.
+    #[serde(rename = "ANSWER_STYLE_UNSPECIFIED")]
+    AnswerStyleUnspecified,
+    /// Succint but abstract style.
+    #[serde(rename = "ABSTRACTIVE")]
+    #[default]
+    Abstractive,
+    /// Very brief and extractive style.
+    #[serde(rename = "EXTRACTIVE")]
+    Extractive,
+    /// Verbose style including extra details. The response may be formatted as a sentence, paragraph, multiple
+    /// paragraphs, or bullet points, etc.
+    #[serde(rename = "VERBOSE")]
+    Verbose,
+}
+
+/// A repeated list of passages.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroundingPassages {
+    /// List of passages.
+    pub passages: Vec<GroundingPassage>,
+}
+
+/// Passage included inline with a grounding configuration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroundingPassage {
+    /// Identifier for the passage for attributing this passage in grounded answers.
+    pub id: String,
+    /// Content of the passage.
+    pub content: Content,
+}
+
+/// Configuration for retrieving grounding content from a Corpus or Document created using the Semantic Retriever
+/// API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SemanticRetrieverConfig {
+    /// Required. Name of the resource for retrieval. Example: corpora/123 or corpora/123/documents/abc.
+    pub source: String,
+    /// Required. Query to use for matching Chunks in the given resource by similarity.
+    pub query: Content,
+    /// Optional. Filters for selecting Documents and/or Chunks from the resource.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_filters: Option<Vec<MetadataFilter>>,
+    /// Optional. Maximum number of relevant Chunks to retrieve.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_chunks_count: Option<isize>,
+    /// Optional. Minimum relevance score for retrieved relevant Chunks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum_relevance_score: Option<f64>,
+}
+
+/// User provided filter to limit retrieval based on Chunk or Document level metadata values.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MetadataFilter {
+    /// Required. The key of the metadata to filter on.
+    pub key: String,
+    /// Required. The Conditions for the given key that will trigger this filter. Multiple Conditions are joined by
+    /// logical ORs.
+    pub conditions: Vec<Condition>,
+}
+
+/// Filter condition applicable to a single key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    /// Required. Operator applied to the given key-value pair to trigger the condition.
+    pub operation: Operator,
+    /// The string value to filter the metadata on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub string_value: Option<String>,
+    /// The numeric value to filter the metadata on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numeric_value: Option<f64>,
+}
+
+/// Defines the valid operators that can be applied to a key-value pair.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Operator {
+    /// The default value. This value is unused.
+    #[serde(rename = "OPERATOR_UNSPECIFIED")]
+    OperatorUnspecified,
+    /// Supported by numeric.
+    #[serde(rename = "LESS")]
+    Less,
+    /// Supported by numeric.
+    #[serde(rename = "LESS_EQUAL")]
+    LessEqual,
+    /// Supported by numeric & string.
+    #[serde(rename = "EQUAL")]
+    Equal,
+    /// Supported by numeric.
+    #[serde(rename = "GREATER_EQUAL")]
+    GreaterEqual,
+    /// Supported by numeric.
+    #[serde(rename = "GREATER")]
+    Greater,
+    /// Supported by numeric & string.
+    #[serde(rename = "NOT_EQUAL")]
+    NotEqual,
+    /// Supported by string only when CustomMetadata value type for the given key has a stringListValue.
+    #[serde(rename = "INCLUDES")]
+    Includes,
+    /// Supported by string only when CustomMetadata value type for the given key has a stringListValue.
+    #[serde(rename = "EXCLUDES")]
+    Excludes,
+}
+
+/// Counts the number of tokens in a prompt.
+///
+/// The whole generateContent request is sent so that the system instruction, tools and cached content are counted
+/// as well.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CountTokensRequest {
+    /// Optional. The overall input given to the Model. This includes the prompt as well as other model steering
+    /// information like system instructions, and/or function declarations for function calling.
+    pub generate_content_request: GeminiRequestBody,
+}
+
+/// Creates a cached content resource, bound to a model, to reuse the same large context across requests.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateCachedContentRequest {
+    /// Required. Immutable. The name of the Model to use for cached content. Format: models/{model}
+    pub model: String,
+    /// Optional. Immutable. The user-generated meaningful display name of the cached content. Maximum 128 Unicode
+    /// characters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Optional. Input only. Immutable. The content to cache.
+    pub contents: Vec<Content>,
+    /// Optional. Input only. Immutable. Developer set system instruction. Currently text only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+    /// Input only. New TTL for this resource, input only. A duration in seconds with up to nine fractional digits,
+    /// ending with 's'. Example: "3.5s".
+    pub ttl: String,
+}
+
+/// Request containing the Content for the model to embed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmbedContentRequest {
+    /// Required. The model's resource name. Format: models/{model}
+    pub model: String,
+    /// Required. The content to embed. Only the parts.text fields will be counted.
+    pub content: Content,
+}
+
+/// Batch request to get embeddings from the model for a list of prompts.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEmbedContentsRequest {
+    /// Required. Embed requests for the batch. The model in each of these requests must match the model specified
+    /// BatchEmbedContentsRequest.model.
+    pub requests: Vec<EmbedContentRequest>,
+}
+
 /// Configuration options for model generation and outputs. Not all parameters are configurable for every model.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -68,6 +283,10 @@ pub struct GenerationConfig {
     /// details.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub response_schema: Option<Schema>,
+    /// Optional. Output schema of the generated response. This is an alternative to responseSchema that accepts JSON
+    /// Schema. If set, responseSchema must be omitted, but responseMimeType is required.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_json_schema: Option<serde_json::Value>,
     /// Optional. Number of generated responses to return.
     /// Currently, this value can only be set to 1. If unset, this will default to 1.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -102,26 +321,99 @@ pub struct GenerationConfig {
     pub top_k: Option<isize>,
 }
 
+/// `top_k` and `top_p` are left unset so that the defaults of the model apply.
 impl Default for GenerationConfig {
     fn default() -> Self {
         Self {
             temperature: Some(1.0),
-            top_k: Some(64),
-            top_p: Some(0.95),
+            top_k: None,
+            top_p: None,
             max_output_tokens: Some(8192),
             response_mime_type: Some("text/plain".into()),
             stop_sequences: None,
             response_schema: None,
+            response_json_schema: None,
             candidate_count: None,
         }
     }
 }
 
+/// Compares the canonical serialized form, so that the configs are equal if they serialize to the same request.
+impl PartialEq for GenerationConfig {
+    fn eq(&self, other: &Self) -> bool {
+        canonical(self) == canonical(other)
+    }
+}
+
+impl Eq for GenerationConfig {}
+
+/// Hashes the canonical serialized form, consistently with `PartialEq`.
+impl Hash for GenerationConfig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        canonical(self).hash(state)
+    }
+}
+
+impl GenerationConfig {
+    /// Sets a JSON Schema as the output schema, the response MIME type is set to `application/json`.
+    ///
+    /// Fails if `response_schema` is already set, the two are mutually exclusive.
+    pub fn set_response_json_schema(&mut self, schema: serde_json::Value) -> Result<()> {
+        if self.response_schema.is_some() {
+            bail!("response_json_schema and response_schema are mutually exclusive");
+        }
+        self.response_json_schema = Some(schema);
+        self.response_mime_type = Some("application/json".into());
+        Ok(())
+    }
+
+    /// Switches back to plain text output, the response MIME type is set to `text/plain` and both output schemas are
+    /// cleared.
+    pub fn set_response_text(&mut self) {
+        self.response_mime_type = Some("text/plain".into());
+        self.response_schema = None;
+        self.response_json_schema = None;
+    }
+
+    /// Lists the fields that differ between the two configs, e.g. to catch config drift between environments.
+    ///
+    /// Each difference is the field name with the values of `self` and `other` rendered as JSON, `null` if unset.
+    pub fn diff(&self, other: &Self) -> Vec<(&'static str, String, String)> {
+        const FIELDS: [(&str, &str); 9] = [
+            ("stop_sequences", "stopSequences"),
+            ("response_mime_type", "responseMimeType"),
+            ("response_schema", "responseSchema"),
+            ("response_json_schema", "responseJsonSchema"),
+            ("candidate_count", "candidateCount"),
+            ("max_output_tokens", "maxOutputTokens"),
+            ("temperature", "temperature"),
+            ("top_p", "topP"),
+            ("top_k", "topK"),
+        ];
+        let (a, b) = (canonical(self), canonical(other));
+        FIELDS
+            .into_iter()
+            .filter_map(|(field, key)| {
+                let (a, b) = (&a[key], &b[key]);
+                (a != b).then(|| (field, a.to_string(), b.to_string()))
+            })
+            .collect()
+    }
+
+    /// Checks that the config is valid before sending it.
+    pub fn validate(&self) -> Result<()> {
+        if self.response_schema.is_some() && self.response_json_schema.is_some() {
+            bail!("response_json_schema and response_schema are mutually exclusive");
+        }
+        Ok(())
+    }
+}
+
 /// Tool details that the model may use to generate response.
 ///
 /// A Tool is a piece of code that enables the system tointeract with external systems to perform an action, or set of
 /// actions, outside of knowledge and scope of the model.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Tool {
     /// Optional. A list of FunctionDeclarations available to the model that can be used for function calling.
@@ -136,6 +428,9 @@ pub struct Tool {
     /// Optional. Enables the model to execute code as part of generation.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub code_execution: Option<CodeExecution>,
+    /// Optional. GoogleSearch tool type. Tool to support Google Search in Model. Powered by Google.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub google_search: Option<GoogleSearch>,
 }
 
 /// Structured representation of a function declaration as defined by the OpenAPI 3.03 specification.
@@ -232,6 +527,12 @@ pub enum Type {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CodeExecution;
 
+/// This type has no fields.
+///
+/// GoogleSearch tool type. Tool to support Google Search in Model. Powered by Google.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GoogleSearch {}
+
 /// The Tool configuration containing parameters for specifying Tool use in the request.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -278,7 +579,7 @@ pub enum Mode {
 
 /// Safety setting, affecting the safety-blocking behavior.
 /// Passing a safety setting for a category changes the allowed probability that content is blocked.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SafetySetting {
     /// Required. The category for this setting.
     pub category: HarmCategory,
@@ -286,10 +587,81 @@ pub struct SafetySetting {
     pub threshold: HarmBlockThreshold,
 }
 
+/// Builder of the safety settings, with one method per harm category supported by Gemini.
+///
+/// Setting a category again replaces its threshold, so the built list holds at most one setting per category, e.g.
+/// `SafetySettings::new().all(HarmBlockThreshold::BlockNone).build()` disables blocking across the board.
+#[derive(Clone, Debug, Default)]
+pub struct SafetySettings {
+    settings: Vec<SafetySetting>,
+}
+
+impl SafetySettings {
+    /// The harm categories supported by Gemini.
+    const CATEGORIES: [HarmCategory; 4] = [
+        HarmCategory::HarmCategoryHarassment,
+        HarmCategory::HarmCategoryHateSpeech,
+        HarmCategory::HarmCategorySexuallyExplicit,
+        HarmCategory::HarmCategoryDangerousContent,
+    ];
+
+    /// Creates an empty builder, the API uses the default setting of every category that isn't set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the threshold of the given category.
+    pub fn category(mut self, category: HarmCategory, threshold: HarmBlockThreshold) -> Self {
+        match self.settings.iter_mut().find(|setting| setting.category == category) {
+            Some(setting) => setting.threshold = threshold,
+            None => self.settings.push(SafetySetting { category, threshold }),
+        }
+        self
+    }
+
+    /// Sets the threshold of all the categories supported by Gemini.
+    pub fn all(self, threshold: HarmBlockThreshold) -> Self {
+        Self::CATEGORIES.into_iter().fold(self, |settings, category| {
+            settings.category(category, threshold.clone())
+        })
+    }
+
+    /// Sets the threshold of harassment content.
+    pub fn harassment(self, threshold: HarmBlockThreshold) -> Self {
+        self.category(HarmCategory::HarmCategoryHarassment, threshold)
+    }
+
+    /// Sets the threshold of hate speech.
+    pub fn hate_speech(self, threshold: HarmBlockThreshold) -> Self {
+        self.category(HarmCategory::HarmCategoryHateSpeech, threshold)
+    }
+
+    /// Sets the threshold of sexually explicit content.
+    pub fn sexually_explicit(self, threshold: HarmBlockThreshold) -> Self {
+        self.category(HarmCategory::HarmCategorySexuallyExplicit, threshold)
+    }
+
+    /// Sets the threshold of dangerous content.
+    pub fn dangerous_content(self, threshold: HarmBlockThreshold) -> Self {
+        self.category(HarmCategory::HarmCategoryDangerousContent, threshold)
+    }
+
+    /// Returns the list of settings, in the order the categories were first set.
+    pub fn build(self) -> Vec<SafetySetting> {
+        self.settings
+    }
+}
+
+impl From<SafetySettings> for Vec<SafetySetting> {
+    fn from(settings: SafetySettings) -> Self {
+        settings.build()
+    }
+}
+
 /// The category of a rating.
 /// These categories cover various kinds of harms that developers may wish to #[derive(Clone, Serialize,
 /// Deserialize)]st.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HarmCategory {
     /// Category is unspecified.
     #[serde(rename = "HARM_CATEGORY_UNSPECIFIED")]
@@ -327,7 +699,7 @@ pub enum HarmCategory {
 }
 
 /// Block at and beyond a specified harm probability.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HarmBlockThreshold {
     /// Threshold is unspecified.
     #[serde(rename = "HARM_BLOCK_THRESHOLD_UNSPECIFIED")]
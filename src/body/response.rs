@@ -1,6 +1,7 @@
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use super::{request::HarmCategory, Content};
+use super::{error::GeminiError, request::HarmCategory, Content, Part, Role};
 
 /// Response from the model supporting multiple candidate responses.
 ///
@@ -14,11 +15,221 @@ use super::{request::HarmCategory, Content};
 #[serde(rename_all = "camelCase")]
 pub struct GenerateContentResponse {
     /// Candidate responses from the model.
+    #[serde(default)]
     pub candidates: Vec<Candidate>,
     /// Returns the prompt's feedback related to the content filters.
     pub prompt_feedback: Option<PromptFeedback>,
     /// Output only. Metadata on the generation requests' token usage.
+    #[serde(default)]
     pub usage_metadata: UsageMetadata,
+    /// Output only. The model version used to generate the response, e.g. `gemini-1.5-flash-002`.
+    pub model_version: Option<String>,
+    /// Not part of the API. Set by the client on the first streamed chunk after the stream reconnected, the
+    /// continuation may repeat some of the text received before the drop.
+    #[serde(skip)]
+    pub reconnected: bool,
+    /// Not part of the API. Set by the client to the resource name of the model the request was sent to, e.g.
+    /// `models/gemini-1.5-flash`.
+    #[serde(skip)]
+    pub requested_model: Option<String>,
+}
+
+impl GenerateContentResponse {
+    /// Deserializes the concatenated text of the first candidate as JSON.
+    ///
+    /// A surrounding markdown code fence (```` ```json ````) is stripped, so this also works when JSON output is only
+    /// asked for in the prompt. Fails with `GeminiError::NoTextPart` if there is no text, or with
+    /// `GeminiError::InvalidJson` if the text can't be deserialized into `T`.
+    pub fn parse_json<T: DeserializeOwned>(&self) -> Result<T> {
+        let Some(candidate) = self.candidates.first() else {
+            bail!(GeminiError::NoTextPart)
+        };
+        if !candidate.content.parts.iter().any(|part| matches!(part, Part::Text(_))) {
+            bail!(GeminiError::NoTextPart)
+        }
+        let text = candidate.content.text();
+        let json = strip_code_fence(&text);
+        serde_json::from_str(json).map_err(|e| {
+            GeminiError::InvalidJson {
+                text: text.clone(),
+                message: e.to_string(),
+            }
+            .into()
+        })
+    }
+}
+
+impl GenerateContentResponse {
+    /// Returns the full content of the first candidate with the model role, for recording it in the history.
+    ///
+    /// All parts are kept, so function calls and code execution results can be sent back verbatim.
+    pub fn to_model_content(&self) -> Option<Content> {
+        let content = &self.candidates.first()?.content;
+        Some(Content {
+            role: Some(Role::Model),
+            parts: content.parts.clone(),
+        })
+    }
+
+    /// Returns the name and the arguments of the first function call of the first candidate.
+    ///
+    /// The arguments are `null` if the model didn't provide any.
+    pub fn function_call(&self) -> Option<(&str, &serde_json::Value)> {
+        self.function_calls().into_iter().next()
+    }
+
+    /// Returns the names and the arguments of all function calls of the first candidate, in order.
+    ///
+    /// The model may request several calls in parallel within one response. The arguments are `null` if the model
+    /// didn't provide any.
+    pub fn function_calls(&self) -> Vec<(&str, &serde_json::Value)> {
+        static NO_ARGS: serde_json::Value = serde_json::Value::Null;
+        let Some(candidate) = self.candidates.first() else {
+            return Vec::new();
+        };
+        candidate
+            .content
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::FunctionCall(call) => Some((call.name.as_str(), call.args.as_ref().unwrap_or(&NO_ARGS))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the MIME types and the base64 data of all images of the first candidate, in order.
+    #[cfg(feature = "image_analysis")]
+    pub fn images(&self) -> Vec<(&str, &str)> {
+        let Some(candidate) = self.candidates.first() else {
+            return Vec::new();
+        };
+        candidate
+            .content
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::InlineData { mime_type, data, .. } if mime_type.starts_with("image/") => {
+                    Some((mime_type.as_str(), data.as_str()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Saves all images of the first candidate in the given directory, creating it if needed.
+    ///
+    /// The images are named `image_{index}` with the extension of their MIME type. Returns the paths of the saved
+    /// images, in order.
+    #[cfg(feature = "image_analysis")]
+    pub fn save_all_images(&self, dir: impl AsRef<std::path::Path>) -> Result<Vec<std::path::PathBuf>> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        self.images()
+            .into_iter()
+            .enumerate()
+            .map(|(index, (mime_type, data))| {
+                let extension = image::ImageFormat::from_mime_type(mime_type)
+                    .and_then(|format| format.extensions_str().first().copied())
+                    .unwrap_or("bin");
+                let path = dir.join(format!("image_{}.{}", index, extension));
+                std::fs::write(&path, crate::utils::image::decode_base64(data)?)?;
+                Ok(path)
+            })
+            .collect()
+    }
+
+    /// Fails with `GeminiError::PromptBlocked` if the prompt was blocked and no candidates are returned.
+    pub fn check_prompt_blocked(&self) -> Result<()> {
+        if !self.candidates.is_empty() {
+            return Ok(());
+        }
+        match &self.prompt_feedback {
+            Some(PromptFeedback {
+                block_reason: Some(block_reason),
+                safety_ratings,
+            }) => bail!(GeminiError::PromptBlocked {
+                block_reason: block_reason.clone(),
+                safety_ratings: safety_ratings.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Merges a chunk of a streamed response into this response.
+    ///
+    /// Consecutive text parts are concatenated and the latest finish reason, ratings and metadata are kept. The usage
+    /// metadata of the chunks is cumulative, so the counts of the latest chunk that reports them are kept.
+    pub fn merge_chunk(&mut self, chunk: GenerateContentResponse) {
+        for candidate in chunk.candidates {
+            let Some(merged) = self.candidates.iter_mut().find(|c| c.index == candidate.index) else {
+                self.candidates.push(candidate);
+                continue;
+            };
+            for part in candidate.content.parts {
+                match (merged.content.parts.last_mut(), part) {
+                    (Some(Part::Text(text)), Part::Text(delta)) => text.push_str(&delta),
+                    (_, part) => merged.content.parts.push(part),
+                }
+            }
+            if candidate.finish_reason.is_some() {
+                merged.finish_reason = candidate.finish_reason;
+            }
+            if candidate.safety_ratings.is_some() {
+                merged.safety_ratings = candidate.safety_ratings;
+            }
+            if candidate.citation_metadata.is_some() {
+                merged.citation_metadata = candidate.citation_metadata;
+            }
+            if candidate.grounding_metadata.is_some() {
+                merged.grounding_metadata = candidate.grounding_metadata;
+            }
+            if candidate.token_count.is_some() {
+                merged.token_count = candidate.token_count;
+            }
+        }
+        if chunk.prompt_feedback.is_some() {
+            self.prompt_feedback = chunk.prompt_feedback;
+        }
+        if chunk.usage_metadata.total_token_count > 0 {
+            self.usage_metadata = chunk.usage_metadata;
+        }
+        if chunk.model_version.is_some() {
+            self.model_version = chunk.model_version;
+        }
+    }
+
+    /// Returns the model that generated the response, e.g. to log which model answered when using custom models.
+    ///
+    /// This is the `modelVersion` reported by the API if present, otherwise the model the client sent the request to.
+    pub fn model_version(&self) -> Option<&str> {
+        self.model_version.as_deref().or(self.requested_model.as_deref())
+    }
+
+    /// Returns the finish reason of the first candidate, or `None` if the model hasn't finished yet.
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.candidates.first()?.finish_reason.as_ref()
+    }
+
+    /// Returns whether the first candidate was cut off by the max output tokens rather than completing naturally.
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.finish_reason(), Some(FinishReason::MaxTokens))
+    }
+
+    /// Returns the grounding metadata of the first candidate, if the response was grounded with Google Search.
+    pub fn grounding_metadata(&self) -> Option<&GroundingMetadata> {
+        self.candidates.first()?.grounding_metadata.as_ref()
+    }
+}
+
+/// Strips a surrounding markdown code fence from the text, if any.
+pub(crate) fn strip_code_fence(text: &str) -> &str {
+    let text = text.trim();
+    match text.strip_prefix("```").and_then(|s| s.strip_suffix("```")) {
+        // Drop the language tag of the fence, e.g. ```json
+        Some(inner) => inner.split_once('\n').map_or(inner, |(_, code)| code).trim(),
+        None => text,
+    }
 }
 
 /// A response candidate generated from the model.
@@ -43,6 +254,9 @@ pub struct Candidate {
     /// This field is populated for GenerateAnswer calls.
     #[deprecated(since = "1.0.0")]
     pub grounding_attributions: Option<Vec<GroundingAttribution>>,
+    /// Output only. Grounding metadata for the candidate.
+    /// This field is populated for GenerateContent calls with the Google Search tool enabled.
+    pub grounding_metadata: Option<GroundingMetadata>,
     /// Output only. Index of the candidate in the list of response candidates.
     pub index: Option<isize>,
     /// Output only.
@@ -51,6 +265,117 @@ pub struct Candidate {
     pub logprobs_result: Option<LogprobsResult>,
 }
 
+impl Candidate {
+    /// Returns the top candidate tokens at the given decoding step, sorted by log probability in descending order.
+    ///
+    /// Returns `None` if the logprobs were not requested or the step is out of range.
+    pub fn top_tokens(&self, step: usize) -> Option<&[Candidate1]> {
+        let top_candidates = self.logprobs_result.as_ref()?.top_candidates.get(step)?;
+        Some(&top_candidates.candidates)
+    }
+
+    /// Returns the chosen token of each decoding step with its probability, e.g. to visualize the token confidence.
+    ///
+    /// The list is empty if the logprobs were not requested. A token without a log probability gets a probability of 0.
+    pub fn chosen_tokens(&self) -> Vec<(String, f64)> {
+        let Some(logprobs_result) = &self.logprobs_result else {
+            return Vec::new();
+        };
+        logprobs_result
+            .chosen_candidates
+            .iter()
+            .map(|candidate| {
+                let token = candidate.token.clone().unwrap_or_default();
+                (token, candidate.probability().unwrap_or(0.0))
+            })
+            .collect()
+    }
+}
+
+/// Metadata returned to client when grounding is enabled.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroundingMetadata {
+    /// List of supporting references retrieved from specified grounding source.
+    pub grounding_chunks: Option<Vec<GroundingChunk>>,
+    /// List of grounding support.
+    pub grounding_supports: Option<Vec<GroundingSupport>>,
+    /// Web search queries for the following-up web search.
+    pub web_search_queries: Option<Vec<String>>,
+    /// Optional. Google search entry for the following-up web searches.
+    pub search_entry_point: Option<SearchEntryPoint>,
+    /// Metadata related to retrieval in the grounding flow.
+    pub retrieval_metadata: Option<RetrievalMetadata>,
+}
+
+/// Grounding chunk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroundingChunk {
+    /// Grounding chunk from the web.
+    pub web: Option<Web>,
+}
+
+/// Chunk from the web.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Web {
+    /// URI reference of the chunk.
+    pub uri: Option<String>,
+    /// Title of the chunk.
+    pub title: Option<String>,
+}
+
+/// Grounding support.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroundingSupport {
+    /// A list of indices (into 'grounding_chunk') specifying the citations associated with the claim. For instance
+    /// [1,3,4] means that grounding_chunk[1], grounding_chunk[3], grounding_chunk[4] are the retrieved content
+    /// attributed to the claim.
+    pub grounding_chunk_indices: Option<Vec<isize>>,
+    /// Confidence score of the support references. Ranges from 0 to 1. 1 is the most confident. This list must have
+    /// the same size as the groundingChunkIndices.
+    pub confidence_scores: Option<Vec<f64>>,
+    /// Segment of the content this support belongs to.
+    pub segment: Option<Segment>,
+}
+
+/// Segment of the content.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+    /// Output only. The index of a Part object within its parent Content object.
+    pub part_index: Option<isize>,
+    /// Output only. Start index in the given Part, measured in bytes. Offset from the start of the Part, inclusive,
+    /// starting at zero.
+    pub start_index: Option<isize>,
+    /// Output only. End index in the given Part, measured in bytes. Offset from the start of the Part, exclusive,
+    /// starting at zero.
+    pub end_index: Option<isize>,
+    /// Output only. The text corresponding to the segment from the response.
+    pub text: Option<String>,
+}
+
+/// Google search entry point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchEntryPoint {
+    /// Optional. Web content snippet that can be embedded in a web page or an app webview.
+    pub rendered_content: Option<String>,
+    /// Optional. Base64 encoded JSON representing array of <search term, search url> tuple.
+    pub sdk_blob: Option<String>,
+}
+
+/// Metadata related to retrieval in the grounding flow.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetrievalMetadata {
+    /// Optional. Score indicating how likely information from google search could help answer the prompt. The score
+    /// is in the range [0, 1], where 0 is the least likely and 1 is the most likely. This score is only populated when
+    /// google search grounding and dynamic retrieval is enabled. It will be compared to the threshold to determine
+    /// whether to trigger google search.
+    pub google_search_dynamic_retrieval_score: Option<f64>,
+}
+
 /// Logprobs Result
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -80,6 +405,13 @@ pub struct Candidate1 {
     pub log_probability: Option<f64>,
 }
 
+impl Candidate1 {
+    /// Returns the probability of the token, converted from its log probability.
+    pub fn probability(&self) -> Option<f64> {
+        self.log_probability.map(f64::exp)
+    }
+}
+
 /// Defines the reason why the model stopped generating tokens.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FinishReason {
@@ -158,8 +490,10 @@ pub enum HarmProbability {
 }
 
 /// Metadata on the generation request's token usage.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+///
+/// Counts missing from the response, e.g. in the chunks of a streamed response, default to 0.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
 pub struct UsageMetadata {
     /// Number of tokens in the prompt. When cachedContent is set, this is still the total effective prompt size
     /// meaning this includes the number of tokens in the cached content.
@@ -172,6 +506,15 @@ pub struct UsageMetadata {
     pub total_token_count: isize,
 }
 
+impl UsageMetadata {
+    /// Returns the number of prompt tokens that were not served from the cached content.
+    ///
+    /// The prompt token count includes the cached tokens, so this is the part of the prompt billed at the full rate.
+    pub fn fresh_prompt_tokens(&self) -> isize {
+        self.prompt_token_count - self.cached_content_token_count.unwrap_or(0)
+    }
+}
+
 /// A collection of source attributions for a piece of content.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -211,9 +554,9 @@ pub struct GroundingAttribution {
 #[serde(rename_all = "camelCase")]
 pub struct AttributionSourceId {
     /// Identifier for an inline passage.
-    pub grounding_passage: GroundingPassageId,
+    pub grounding_passage: Option<GroundingPassageId>,
     /// Identifier for a Chunk fetched via Semantic Retriever.
-    pub semantic_retriever_chunk: SemanticRetrieverChunk,
+    pub semantic_retriever_chunk: Option<SemanticRetrieverChunk>,
 }
 
 /// Identifier for a part within a GroundingPassage.
@@ -244,7 +587,8 @@ pub struct PromptFeedback {
     /// Optional. If set, the prompt was blocked and no candidates are returned. Rephrase the prompt.
     pub block_reason: Option<BlockReason>,
     /// Ratings for safety of the prompt. There is at most one rating per category.
-    pub safety_ratings: SafetyRating,
+    #[serde(default)]
+    pub safety_ratings: Vec<SafetyRating>,
 }
 
 /// Specifies the reason why the prompt was blocked.
@@ -267,6 +611,147 @@ pub enum BlockReason {
     ProhibitedContent,
 }
 
+/// Response from the model for a grounded answer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateAnswerResponse {
+    /// Candidate answer from the model.
+    /// Note: The model always attempts to provide a grounded answer, even when the answer is unlikely to be
+    /// answerable from the given passages. In that case, a low-quality or ungrounded answer may be provided, along
+    /// with a low answerableProbability.
+    pub answer: Candidate,
+    /// Output only. The model's estimate of the probability that its answer is correct and grounded in the input
+    /// passages.
+    pub answerable_probability: Option<f64>,
+    /// Output only. Feedback related to the input data used to answer the question, as opposed to the
+    /// model-generated response to the question.
+    pub input_feedback: Option<InputFeedback>,
+}
+
+impl GenerateAnswerResponse {
+    /// Returns the sources that contributed to the answer, inline passages or Semantic Retriever chunks.
+    pub fn grounding_attributions(&self) -> &[GroundingAttribution] {
+        #[allow(deprecated)]
+        self.answer.grounding_attributions.as_deref().unwrap_or_default()
+    }
+}
+
+/// Feedback related to the input data used to answer the question, as opposed to the model-generated response to the
+/// question.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputFeedback {
+    /// Optional. If set, the input was blocked and no candidates are returned. Rephrase the input.
+    pub block_reason: Option<BlockReason>,
+    /// Ratings for safety of the input. There is at most one rating per category.
+    pub safety_ratings: Option<Vec<SafetyRating>>,
+}
+
+/// A response from countTokens.
+///
+/// It returns the model's tokenCount for the prompt.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CountTokensResponse {
+    /// The number of tokens that the Model tokenizes the prompt into. Always non-negative.
+    pub total_tokens: isize,
+    /// Number of tokens in the cached part of the prompt (the cached content).
+    pub cached_content_token_count: Option<isize>,
+}
+
+/// The response to a BatchEmbedContentsRequest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchEmbedContentsResponse {
+    /// Output only. The embeddings for each request, in the same order as provided in the batch request.
+    #[serde(default)]
+    pub embeddings: Vec<ContentEmbedding>,
+}
+
+/// A list of floats representing an embedding.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentEmbedding {
+    /// The embedding values.
+    pub values: Vec<f32>,
+}
+
+/// Response for media.upload.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadFileResponse {
+    /// Metadata for the created file.
+    pub file: File,
+}
+
+/// Content that has been preprocessed and can be used in subsequent requests to the model.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedContent {
+    /// Output only. Identifier. The resource name referring to the cached content. Format:
+    /// cachedContents/{id}
+    pub name: String,
+    /// Required. Immutable. The name of the Model the cached content is bound to. Format: models/{model}
+    pub model: String,
+    /// Optional. Immutable. The user-generated meaningful display name of the cached content.
+    pub display_name: Option<String>,
+    /// Output only. Creation time of the cache entry.
+    pub create_time: Option<String>,
+    /// Output only. When the cache entry was last updated in UTC time.
+    pub update_time: Option<String>,
+    /// Timestamp in UTC of when this resource is considered expired.
+    pub expire_time: Option<String>,
+    /// Output only. Metadata on the usage of the cached content.
+    pub usage_metadata: Option<CachedContentUsageMetadata>,
+}
+
+/// Metadata on the usage of the cached content.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedContentUsageMetadata {
+    /// Total number of tokens that the cached content consumes.
+    pub total_token_count: Option<isize>,
+}
+
+/// A file uploaded to the API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct File {
+    /// Immutable. Identifier. The File resource name. Example: files/123-456
+    pub name: String,
+    /// Optional. The human-readable display name for the File.
+    pub display_name: Option<String>,
+    /// Output only. MIME type of the file.
+    pub mime_type: Option<String>,
+    /// Output only. Size of the file in bytes.
+    pub size_bytes: Option<String>,
+    /// Output only. The timestamp of when the File was created.
+    pub create_time: Option<String>,
+    /// Output only. The timestamp of when the File will be deleted. Only set if the File is scheduled to expire.
+    pub expiration_time: Option<String>,
+    /// Output only. The uri of the File.
+    pub uri: String,
+    /// Output only. Processing state of the File.
+    pub state: Option<FileState>,
+}
+
+/// States for the lifecycle of a File.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum FileState {
+    /// The default value. This value is used if the state is omitted.
+    #[serde(rename = "STATE_UNSPECIFIED")]
+    StateUnspecified,
+    /// File is being processed and cannot be used for inference yet.
+    #[serde(rename = "PROCESSING")]
+    Processing,
+    /// File is processed and available for inference.
+    #[serde(rename = "ACTIVE")]
+    Active,
+    /// File failed processing.
+    #[serde(rename = "FAILED")]
+    Failed,
+}
+
 /// Response from ListModel containing a paginated list of Models.
 ///
 /// If successful, the response body contains data with the following structure
@@ -321,3 +806,45 @@ pub struct Model {
     /// isn't allowed as a generation parameter.
     pub top_k: Option<isize>,
 }
+
+impl Model {
+    /// Parses the family, version and variant from the model name, e.g. `models/gemini-1.5-flash-001` is parsed into
+    /// family `gemini`, version 1.5 and variant `flash`.
+    ///
+    /// Returns `None` if the name doesn't contain a `major.minor` version.
+    pub fn parse_version(&self) -> Option<ModelVersion> {
+        let name = self.name.rsplit('/').next()?;
+        let mut segments = name.split('-');
+        let family = segments.next()?.to_owned();
+        let (major, minor) = segments.next()?.split_once('.')?;
+        Some(ModelVersion {
+            family,
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            variant: segments.next().map(str::to_owned),
+        })
+    }
+}
+
+/// The structured version of a model, parsed from its name.
+///
+/// Versions are ordered by family, major, minor and then variant, so `version.major_minor() >= (1, 5)` checks for
+/// 1.5 or newer.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ModelVersion {
+    /// The model family, e.g. `gemini`.
+    pub family: String,
+    /// The major version, e.g. 1 in 1.5.
+    pub major: u32,
+    /// The minor version, e.g. 5 in 1.5.
+    pub minor: u32,
+    /// The model variant, e.g. `flash` or `pro`.
+    pub variant: Option<String>,
+}
+
+impl ModelVersion {
+    /// Returns the `(major, minor)` version for comparisons.
+    pub fn major_minor(&self) -> (u32, u32) {
+        (self.major, self.minor)
+    }
+}
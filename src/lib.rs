@@ -6,15 +6,16 @@ pub mod utils;
 use anyhow::{bail, Result};
 use body::response::{Model, ModelsResponse};
 use reqwest::Client;
+use utils::redact::redact_error;
 
 /// Get a list of available models from Gemini API
 pub async fn get_models(key: String) -> Result<Vec<Model>> {
     let url = "https://generativelanguage.googleapis.com/v1beta/models";
     let url = format!("{}?key={}", url, key);
     let client = Client::new();
-    let response = client.get(url).send().await?;
+    let response = client.get(url).send().await.map_err(redact_error)?;
     if response.status().is_success() {
-        let response_text = response.text().await?;
+        let response_text = response.text().await.map_err(redact_error)?;
         let response: ModelsResponse = serde_json::from_str(&response_text)?;
         Ok(response.models)
     } else {
@@ -22,12 +23,71 @@ pub async fn get_models(key: String) -> Result<Vec<Model>> {
     }
 }
 
+/// Get a list of available models that support the given generation method, e.g. `generateContent`, `embedContent`
+pub async fn get_models_supporting(key: String, method: &str) -> Result<Vec<Model>> {
+    let models = get_models(key).await?;
+    Ok(models
+        .into_iter()
+        .filter(|model| model.supported_generation_methods.iter().any(|m| m == method))
+        .collect())
+}
+
+/// A list of available models fetched once from Gemini API, to look up models without hitting the network each time
+#[derive(Clone)]
+pub struct ModelCatalog {
+    key: String,
+    models: Vec<Model>,
+}
+
+impl ModelCatalog {
+    /// Fetch the list of available models and cache it
+    pub async fn load(key: String) -> Result<Self> {
+        let models = get_models(key.clone()).await?;
+        Ok(Self { key, models })
+    }
+
+    /// Fetch the list of available models again, replacing the cached one
+    pub async fn refresh(&mut self) -> Result<()> {
+        self.models = get_models(self.key.clone()).await?;
+        Ok(())
+    }
+
+    /// Get a cached model by its name, with or without the `models/` prefix
+    pub fn get(&self, name: &str) -> Option<&Model> {
+        let name = name.strip_prefix("models/").unwrap_or(name);
+        self.models
+            .iter()
+            .find(|model| model.name.strip_prefix("models/").unwrap_or(&model.name) == name)
+    }
+
+    /// Get all the cached models
+    pub fn all(&self) -> &[Model] {
+        &self.models
+    }
+
+    /// Get the cached models that support the given generation method, e.g. `generateContent`, `embedContent`
+    pub fn supporting(&self, method: &str) -> Vec<&Model> {
+        self.models
+            .iter()
+            .filter(|model| model.supported_generation_methods.iter().any(|m| m == method))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use body::{
-        request::{GeminiRequestBody, GenerationConfig},
-        Content, Part, Role,
+        error::{is_retryable_error, ErrorDetail, GeminiError},
+        request::{
+            GeminiRequestBody, GenerateAnswerRequest, GenerationConfig, GoogleSearch, HarmBlockThreshold, HarmCategory,
+            SafetySetting, SafetySettings, Schema, SemanticRetrieverConfig, Tool, Type,
+        },
+        response::{
+            BlockReason, CachedContent, FinishReason, GenerateAnswerResponse, GenerateContentResponse, HarmProbability,
+            UsageMetadata,
+        },
+        Content, FunctionResponse, Part, Role, VideoMetadata,
     };
     use serde::{Deserialize, Serialize};
 
@@ -46,7 +106,811 @@ mod tests {
         let body_json = serde_json::to_string(&body)?;
         assert_eq!(
             body_json,
-            r#"{"contents":[{"parts":[{"text":"Hello, world!"}],"role":"user"}],"generationConfig":{"responseMimeType":"text/plain","maxOutputTokens":8192,"temperature":1.0,"topP":0.95,"topK":64}}"#
+            r#"{"contents":[{"parts":[{"text":"Hello, world!"}],"role":"user"}],"generationConfig":{"responseMimeType":"text/plain","maxOutputTokens":8192,"temperature":1.0}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn request_fingerprint_is_stable() {
+        let body = GeminiRequestBody {
+            contents: vec![(Role::User, "Hello, world!".to_owned()).into()],
+            generation_config: Some(GenerationConfig::default()),
+            ..Default::default()
+        };
+        let fingerprint = body.fingerprint();
+        assert_eq!(fingerprint.len(), 16);
+        assert_eq!(fingerprint, body.clone().fingerprint());
+        let other = GeminiRequestBody {
+            contents: vec![(Role::User, "Hello, Reine!".to_owned()).into()],
+            ..body
+        };
+        assert_ne!(fingerprint, other.fingerprint());
+    }
+
+    #[test]
+    fn video_metadata_to_json() -> Result<()> {
+        let part = Part::FileData {
+            mime_type: Some("video/mp4".into()),
+            file_uri: "https://generativelanguage.googleapis.com/v1beta/files/abc-123".into(),
+            video_metadata: Some(VideoMetadata {
+                start_offset: Some("10s".into()),
+                end_offset: Some("60s".into()),
+                fps: Some(0.5),
+            }),
+        };
+        let part_json = serde_json::to_string(&part)?;
+        assert_eq!(
+            part_json,
+            r#"{"fileData":{"mimeType":"video/mp4","fileUri":"https://generativelanguage.googleapis.com/v1beta/files/abc-123","videoMetadata":{"startOffset":"10s","endOffset":"60s","fps":0.5}}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn content_role_text_round_trip() {
+        let content: Content = (Role::User, "Hello, world!".to_owned()).into();
+        let (role, text) = content.as_role_text().unwrap();
+        assert!(matches!(role, Role::User));
+        assert_eq!(text, "Hello, world!");
+        let content = Content {
+            role: Some(Role::Model),
+            parts: vec![Part::FileData {
+                mime_type: None,
+                file_uri: "https://generativelanguage.googleapis.com/v1beta/files/abc-123".into(),
+                video_metadata: None,
+            }],
+        };
+        assert!(content.as_role_text().is_none());
+    }
+
+    #[test]
+    fn role_string_round_trip() -> Result<()> {
+        for role in [Role::User, Role::Model, Role::System, Role::Function] {
+            let role_str = role.to_string();
+            assert_eq!(serde_json::to_string(&role)?, format!(r#""{}""#, role_str));
+            assert_eq!(role_str.parse::<Role>()?, role);
+        }
+        assert!("assistant".parse::<Role>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn function_call_round_trip() -> Result<()> {
+        let part: Part = serde_json::from_str(r#"{"functionCall":{"name":"get_weather","args":{"city":"Tokyo"}}}"#)?;
+        let Part::FunctionCall(call) = part else {
+            panic!("expected a function call part");
+        };
+        assert_eq!(call.name, "get_weather");
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Let me check."},{"functionCall":{"name":"get_weather","args":{"city":"Tokyo"}}}],"role":"model"}}]}"#,
+        )?;
+        assert_eq!(
+            response.function_call(),
+            Some(("get_weather", &serde_json::json!({"city": "Tokyo"})))
+        );
+        let response = Part::FunctionResponse(FunctionResponse::new(call.name, serde_json::json!("sunny")));
+        assert_eq!(
+            serde_json::to_string(&response)?,
+            r#"{"functionResponse":{"name":"get_weather","response":{"result":"sunny"}}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_function_calls() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"functionCall":{"name":"get_weather","args":{"city":"Tokyo"}}},{"functionCall":{"name":"get_weather","args":{"city":"Paris"}}},{"functionCall":{"name":"get_time"}}],"role":"model"}}]}"#,
+        )?;
+        let calls = response.function_calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[1], ("get_weather", &serde_json::json!({"city": "Paris"})));
+        assert_eq!(calls[2], ("get_time", &serde_json::Value::Null));
+        let content = Content {
+            role: Some(Role::Function),
+            parts: vec![
+                Part::FunctionResponse(FunctionResponse::new("get_weather".into(), serde_json::json!("sunny"))),
+                Part::FunctionResponse(FunctionResponse::new("get_weather".into(), serde_json::json!("rainy"))),
+            ],
+        };
+        assert_eq!(
+            serde_json::to_string(&content)?,
+            r#"{"parts":[{"functionResponse":{"name":"get_weather","response":{"result":"sunny"}}},{"functionResponse":{"name":"get_weather","response":{"result":"rainy"}}}],"role":"function"}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_response_json() -> Result<()> {
+        #[derive(Debug, Deserialize)]
+        struct Person {
+            name: String,
+        }
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"```json\n{\"name\": "},{"text":"\"Reine\"}\n```"}],"role":"model"}}]}"#,
+        )?;
+        assert_eq!(response.parse_json::<Person>()?.name, "Reine");
+        let err = response.parse_json::<Vec<String>>().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GeminiError>(),
+            Some(GeminiError::InvalidJson { .. })
+        ));
+        let response: GenerateContentResponse = serde_json::from_str(r#"{"candidates":[]}"#)?;
+        let err = response.parse_json::<Person>().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GeminiError>(),
+            Some(GeminiError::NoTextPart)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn accumulate_stream_json() -> Result<()> {
+        use utils::stream::JsonAccumulator;
+
+        let chunks = [r#"[1, 2"#, r#", 3]"#].map(|text| {
+            let chunk = format!(
+                r#"{{"candidates":[{{"content":{{"parts":[{{"text":"{}"}}]}}}}]}}"#,
+                text
+            );
+            serde_json::from_str::<GenerateContentResponse>(&chunk).map_err(anyhow::Error::from)
+        });
+        let numbers: Vec<i32> = JsonAccumulator::collect(chunks)?;
+        assert_eq!(numbers, vec![1, 2, 3]);
+
+        let chunks = vec![
+            serde_json::from_str(r#"{"candidates":[{"content":{"parts":[{"text":"[1, 2"}]}}]}"#)
+                .map_err(anyhow::Error::from),
+            Err(anyhow::anyhow!("connection reset")),
+        ];
+        let err = JsonAccumulator::collect::<Vec<i32>>(chunks).unwrap_err();
+        match err.downcast_ref::<GeminiError>() {
+            Some(GeminiError::StreamInterrupted { partial, .. }) => assert_eq!(partial, "[1, 2"),
+            _ => panic!("expected an interrupted stream error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn split_streamed_sentences() {
+        use utils::stream::SentenceSplitter;
+
+        let mut splitter = SentenceSplitter::new();
+        assert!(splitter.push("Version 1.").is_empty());
+        assert!(splitter.push("5 is out").is_empty());
+        // 英文句末标点需后接空白，位于末尾时等待下一段文本
+        assert!(splitter.push("!").is_empty());
+        assert_eq!(splitter.push(" Really?! Yes"), ["Version 1.5 is out!", "Really?!"]);
+        assert_eq!(splitter.push("。「好的！」然后"), ["Yes。", "「好的！」"]);
+        assert_eq!(splitter.finish().as_deref(), Some("然后"));
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn preview_partial_stream_json() -> Result<()> {
+        use utils::stream::JsonAccumulator;
+
+        let chunks = [
+            "```json\n{\"name\": \"Rei",
+            "ne\", \"tags\": [\"a\", \"b",
+            "\"], \"age\": tr",
+            "ue, \"nested\": {\"k",
+            "\": 1}}\n```",
+        ];
+        let expected = [
+            serde_json::json!({"name": "Rei"}),
+            serde_json::json!({"name": "Reine", "tags": ["a", "b"]}),
+            serde_json::json!({"name": "Reine", "tags": ["a", "b"]}),
+            serde_json::json!({"name": "Reine", "tags": ["a", "b"], "age": true, "nested": {}}),
+            serde_json::json!({"name": "Reine", "tags": ["a", "b"], "age": true, "nested": {"k": 1}}),
+        ];
+        let mut accumulator = JsonAccumulator::new();
+        for (text, expected) in chunks.iter().zip(expected) {
+            let chunk: GenerateContentResponse =
+                serde_json::from_value(serde_json::json!({"candidates": [{"content": {"parts": [{"text": text}]}}]}))?;
+            accumulator.push(&chunk);
+            assert_eq!(accumulator.partial(), Some(expected));
+        }
+        let value: serde_json::Value = accumulator.finish()?;
+        assert_eq!(value["nested"]["k"], 1);
+        assert_eq!(JsonAccumulator::new().partial(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn guess_media_mime_type() {
+        use utils::mime::guess_mime;
+
+        let cases: [(&[u8], &str); 9] = [
+            (b"\x89PNG\r\n\x1a\n", "image/png"),
+            (b"RIFF\0\0\0\0WEBPVP8 ", "image/webp"),
+            (b"RIFF\0\0\0\0WAVEfmt ", "audio/wav"),
+            (b"ID3\x04\0", "audio/mpeg"),
+            (b"\xff\xf1\x50\x80", "audio/aac"),
+            (b"\0\0\0\x20ftypisom", "video/mp4"),
+            (b"\0\0\0\x14ftypqt  ", "video/quicktime"),
+            (b"\x1a\x45\xdf\xa3\x9f\x42\x82\x84webm", "video/webm"),
+            (b"%PDF-1.7", "application/pdf"),
+        ];
+        for (bytes, mime_type) in cases {
+            assert_eq!(guess_mime(bytes, None).as_deref(), Some(mime_type));
+        }
+        // 内容优先于扩展名
+        assert_eq!(guess_mime(b"%PDF-1.7", Some("mp4")).as_deref(), Some("application/pdf"));
+        assert_eq!(guess_mime(b"hello", Some(".MP3")).as_deref(), Some("audio/mpeg"));
+        assert_eq!(guess_mime(b"hello", Some("unknown")), None);
+        assert_eq!(guess_mime(b"", None), None);
+    }
+
+    #[test]
+    #[cfg(feature = "image_analysis")]
+    fn image_mime_type_fallback() -> Result<()> {
+        use utils::image::image_mime_type;
+
+        // 截断的图片数据无法识别，根据扩展名推断
+        assert_eq!(image_mime_type(b"trunc", "./cat.png")?, "image/png");
+        assert_eq!(
+            image_mime_type(b"trunc", "https://cdn.example.com/cat.webp?size=large#top")?,
+            "image/webp"
+        );
+        assert!(image_mime_type(b"trunc", "https://cdn.example.com/cat").is_err());
+        let buffer = std::fs::read("./file_type_rust.png")?;
+        assert_eq!(image_mime_type(&buffer, "./file_type_rust.jpg")?, "image/png");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_grounding_metadata() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Spain won Euro 2024."}],"role":"model"},"groundingMetadata":{"webSearchQueries":["who won euro 2024"],"searchEntryPoint":{"renderedContent":"<div></div>"},"groundingChunks":[{"web":{"uri":"https://example.com","title":"example.com"}}],"groundingSupports":[{"segment":{"startIndex":0,"endIndex":20,"text":"Spain won Euro 2024."},"groundingChunkIndices":[0],"confidenceScores":[0.9]}]}}]}"#,
+        )?;
+        let metadata = response.grounding_metadata().unwrap();
+        assert_eq!(
+            metadata.web_search_queries.as_deref(),
+            Some(&["who won euro 2024".to_owned()][..])
+        );
+        let chunks = metadata.grounding_chunks.as_ref().unwrap();
+        assert_eq!(chunks[0].web.as_ref().unwrap().title.as_deref(), Some("example.com"));
+        assert!(serde_json::from_str::<GenerateContentResponse>(r#"{"candidates":[]}"#)?
+            .grounding_metadata()
+            .is_none());
+        assert_eq!(
+            serde_json::to_string(&Tool {
+                google_search: Some(GoogleSearch {}),
+                ..Default::default()
+            })?,
+            r#"{"googleSearch":{}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_error_details() -> Result<()> {
+        let error = GeminiError::from_response_text(
+            r#"{"error":{"code":400,"message":"Invalid value","status":"INVALID_ARGUMENT","details":[{"@type":"type.googleapis.com/google.rpc.BadRequest","fieldViolations":[{"field":"generation_config.top_k","description":"Invalid value"}]},{"@type":"type.googleapis.com/google.rpc.RetryInfo","retryDelay":"30s"},{"@type":"type.googleapis.com/google.rpc.Help","links":[]}]}}"#,
+        )?;
+        assert_eq!(error.to_string(), "Invalid value");
+        let GeminiError::Api { code, details, .. } = error else {
+            panic!("expected an api error");
+        };
+        assert_eq!(code, 400);
+        match &details[0] {
+            ErrorDetail::BadRequest { field_violations } => {
+                assert_eq!(field_violations[0].field.as_deref(), Some("generation_config.top_k"))
+            }
+            _ => panic!("expected a bad request detail"),
+        }
+        assert!(matches!(&details[1], ErrorDetail::RetryInfo { retry_delay: Some(delay) } if delay == "30s"));
+        assert!(matches!(details[2], ErrorDetail::Other));
+        Ok(())
+    }
+
+    #[test]
+    fn response_to_model_content() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Let me check."},{"functionCall":{"name":"get_weather","args":{"city":"Tokyo"}}}]},"finishReason":"STOP"}]}"#,
+        )?;
+        let content = response.to_model_content().unwrap();
+        assert_eq!(content.role, Some(Role::Model));
+        assert_eq!(content.parts.len(), 2);
+        assert!(matches!(&content.parts[1], Part::FunctionCall(call) if call.name == "get_weather"));
+        let empty: GenerateContentResponse = serde_json::from_str("{}")?;
+        assert!(empty.to_model_content().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_thought_signature() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Let me check.","thoughtSignature":"c2ln"},{"functionCall":{"name":"get_weather","args":{"city":"Tokyo"}},"thoughtSignature":"c2lnbmF0dXJl"}],"role":"model"},"finishReason":"STOP"}]}"#,
+        )?;
+        let content = response.to_model_content().unwrap();
+        let Part::FunctionCall(call) = &content.parts[1] else {
+            panic!("expected a function call part");
+        };
+        assert_eq!(call.thought_signature.as_deref(), Some("c2lnbmF0dXJl"));
+        let json = serde_json::to_string(&content)?;
+        assert_eq!(
+            json,
+            r#"{"parts":[{"text":"Let me check."},{"functionCall":{"name":"get_weather","args":{"city":"Tokyo"}},"thoughtSignature":"c2lnbmF0dXJl"}],"role":"model"}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "image_analysis")]
+    fn save_inline_data_in_both_base64_variants() -> Result<()> {
+        // 末尾的 0xfb 0xff 在标准编码与 URL 安全编码下分别为 "/v/" 与 "_v_"
+        let bytes = [0x89, b'P', b'N', b'G', 0xfb, 0xff];
+        let path = std::env::temp_dir().join("gemini_api_save_inline_data.png");
+        for data in ["iVBOR/v/", "iVBOR_v_"] {
+            let part = Part::InlineData {
+                mime_type: "image/png".into(),
+                data: data.into(),
+                video_metadata: None,
+            };
+            part.save_inline_data(&path)?;
+            assert_eq!(std::fs::read(&path)?, bytes);
+        }
+        std::fs::remove_file(&path)?;
+        assert!(Part::Text("Hello".into()).save_inline_data(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "image_analysis")]
+    fn save_all_returned_images() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Here are two cats."},{"inline_data":{"mimeType":"image/png","data":"iVBORw=="}},{"inline_data":{"mimeType":"image/jpeg","data":"/9j/4A=="}},{"inline_data":{"mimeType":"audio/wav","data":"UklGRg=="}}],"role":"model"}}]}"#,
+        )?;
+        let images = response.images();
+        assert_eq!(images, [("image/png", "iVBORw=="), ("image/jpeg", "/9j/4A==")]);
+        let dir = std::env::temp_dir().join("gemini_api_save_all_images");
+        let paths = response.save_all_images(&dir)?;
+        assert_eq!(paths, [dir.join("image_0.png"), dir.join("image_1.jpg")]);
+        assert_eq!(std::fs::read(&paths[1])?, [0xff, 0xd8, 0xff, 0xe0]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_consecutive_parts() {
+        let mut content = Content {
+            parts: vec![
+                Part::Text("Hello".into()),
+                Part::Text("Hello".into()),
+                Part::Text("Reine".into()),
+                Part::Text("Hello".into()),
+            ],
+            role: Some(Role::Model),
+        };
+        content.dedup_parts();
+        assert_eq!(content.parts.len(), 3);
+        assert_eq!(content.text(), "HelloReineHello");
+        assert_eq!(content.text_joined("\n"), "Hello\nReine\nHello");
+    }
+
+    #[test]
+    fn extract_text_by_strategy() -> Result<()> {
+        use param::ExtractStrategy;
+
+        let content: Content = serde_json::from_str(
+            r#"{"parts":[{"functionCall":{"name":"get_weather"}},{"text":"Hello"},{"text":" Reine"}],"role":"model"}"#,
+        )?;
+        assert_eq!(ExtractStrategy::default(), ExtractStrategy::ConcatenatedText);
+        assert_eq!(ExtractStrategy::ConcatenatedText.extract(&content), "Hello Reine");
+        assert_eq!(
+            ExtractStrategy::ConcatenatedText.extract_joined(&content, "\n"),
+            "Hello\n Reine"
+        );
+        assert_eq!(ExtractStrategy::FirstText.extract(&content), "Hello");
+        assert_eq!(
+            ExtractStrategy::FirstPart.extract(&content),
+            r#"{"functionCall":{"name":"get_weather"}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hash_request_types() {
+        use std::collections::HashSet;
+
+        let contents: HashSet<Content> = [
+            (Role::User, "Hello".to_owned()).into(),
+            (Role::User, "Hello".to_owned()).into(),
+            (Role::Model, "Hello".to_owned()).into(),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(contents.len(), 2);
+
+        let configs: HashSet<GenerationConfig> = [
+            GenerationConfig::default(),
+            GenerationConfig::default(),
+            GenerationConfig {
+                temperature: Some(0.2),
+                ..Default::default()
+            },
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(configs.len(), 2);
+        // NaN 序列化为 null，与自身相等
+        let nan = GenerationConfig {
+            top_p: Some(f64::NAN),
+            ..Default::default()
+        };
+        assert_eq!(nan, nan.clone());
+    }
+
+    #[test]
+    fn friendly_error_messages() -> Result<()> {
+        let invalid_key = GeminiError::from_response_text(
+            r#"{"error":{"code":400,"message":"API key not valid. Please pass a valid API key.","status":"INVALID_ARGUMENT","details":[{"@type":"type.googleapis.com/google.rpc.ErrorInfo","reason":"API_KEY_INVALID","domain":"googleapis.com"}]}}"#,
+        )?;
+        assert_eq!(
+            invalid_key.user_message(),
+            "The API key is invalid, please check your settings."
+        );
+        // 技术细节仍保留在 Display 中
+        assert!(invalid_key.to_string().contains("API key not valid"));
+        let quota = GeminiError::from_response_text(
+            r#"{"error":{"code":429,"message":"Resource has been exhausted (e.g. check quota).","status":"RESOURCE_EXHAUSTED"}}"#,
+        )?;
+        assert_eq!(
+            quota.user_message(),
+            "The usage quota has been exceeded, please try again later."
+        );
+        let not_found = GeminiError::from_response_text(
+            r#"{"error":{"code":404,"message":"models/gemini-0 is not found for API version v1beta","status":"NOT_FOUND"}}"#,
+        )?;
+        assert_eq!(not_found.user_message(), "The requested model is not available.");
+        let blocked = GeminiError::PromptBlocked {
+            block_reason: BlockReason::Safety,
+            safety_ratings: Vec::new(),
+        };
+        assert_eq!(blocked.user_message(), "The message was blocked for safety reasons.");
+        assert_eq!(
+            GeminiError::NoTextPart.user_message(),
+            "Something went wrong, please try again."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fresh_prompt_tokens() -> Result<()> {
+        let usage: UsageMetadata = serde_json::from_str(
+            r#"{"promptTokenCount":12000,"cachedContentTokenCount":10000,"candidatesTokenCount":100,"totalTokenCount":12100}"#,
+        )?;
+        assert_eq!(usage.fresh_prompt_tokens(), 2000);
+        let usage: UsageMetadata = serde_json::from_str(r#"{"promptTokenCount":12,"totalTokenCount":12}"#)?;
+        assert_eq!(usage.fresh_prompt_tokens(), 12);
+        Ok(())
+    }
+
+    #[test]
+    fn read_token_logprobs() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hi!"}],"role":"model"},"logprobsResult":{"topCandidates":[{"candidates":[{"token":"Hi","logProbability":-0.1},{"token":"Hello","logProbability":-2.5}]},{"candidates":[{"token":"!","logProbability":0.0}]}],"chosenCandidates":[{"token":"Hi","logProbability":-0.1},{"token":"!","logProbability":0.0}]}}]}"#,
+        )?;
+        let candidate = &response.candidates[0];
+        let top_tokens = candidate.top_tokens(0).unwrap();
+        assert_eq!(top_tokens.len(), 2);
+        assert_eq!(top_tokens[1].token.as_deref(), Some("Hello"));
+        assert!(candidate.top_tokens(2).is_none());
+        let chosen = candidate.chosen_tokens();
+        assert_eq!(chosen.len(), 2);
+        assert_eq!(chosen[0].0, "Hi");
+        assert!((chosen[0].1 - (-0.1f64).exp()).abs() < 1e-9);
+        assert_eq!(chosen[1], ("!".to_owned(), 1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_stream_chunks() -> Result<()> {
+        let chunks = [
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hello"}],"role":"model"},"index":0}],"usageMetadata":{"promptTokenCount":4,"totalTokenCount":5}}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":", world"}],"role":"model"},"index":0}]}"#,
+            r#"{"candidates":[{"content":{"parts":[{"text":"!"}],"role":"model"},"finishReason":"STOP","index":0}],"usageMetadata":{"promptTokenCount":4,"candidatesTokenCount":3,"totalTokenCount":7}}"#,
+        ];
+        let mut merged: Option<GenerateContentResponse> = None;
+        for chunk in chunks {
+            let chunk: GenerateContentResponse = serde_json::from_str(chunk)?;
+            match &mut merged {
+                Some(merged) => merged.merge_chunk(chunk),
+                None => merged = Some(chunk),
+            }
+        }
+        let merged = merged.unwrap();
+        assert_eq!(merged.candidates.len(), 1);
+        assert_eq!(merged.candidates[0].content.parts.len(), 1);
+        assert_eq!(merged.candidates[0].content.text(), "Hello, world!");
+        assert!(matches!(merged.candidates[0].finish_reason, Some(FinishReason::Stop)));
+        assert_eq!(merged.usage_metadata.total_token_count, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn semantic_retriever_round_trip() -> Result<()> {
+        let body = GenerateAnswerRequest {
+            contents: vec![(Role::User, "What is the name of the cat?".to_owned()).into()],
+            semantic_retriever: Some(SemanticRetrieverConfig {
+                source: "corpora/123".into(),
+                query: (Role::User, "cat name".to_owned()).into(),
+                metadata_filters: None,
+                max_chunks_count: Some(5),
+                minimum_relevance_score: None,
+            }),
+            ..Default::default()
+        };
+        let body_json = serde_json::to_value(&body)?;
+        assert_eq!(body_json["semanticRetriever"]["source"], "corpora/123");
+        assert_eq!(body_json["semanticRetriever"]["maxChunksCount"], 5);
+        let response: GenerateAnswerResponse = serde_json::from_str(
+            r#"{"answer":{"content":{"parts":[{"text":"Mochi"}],"role":"model"},"finishReason":"STOP","groundingAttributions":[{"sourceId":{"semanticRetrieverChunk":{"source":"corpora/123","chunk":"corpora/123/documents/abc/chunks/xyz"}},"content":{"parts":[{"text":"Reine has a cat named Mochi."}]}}]},"answerableProbability":0.9}"#,
+        )?;
+        let attributions = response.grounding_attributions();
+        assert_eq!(attributions.len(), 1);
+        let chunk = attributions[0].source_id.semantic_retriever_chunk.as_ref().unwrap();
+        assert_eq!(chunk.chunk, "corpora/123/documents/abc/chunks/xyz");
+        Ok(())
+    }
+
+    #[test]
+    fn classify_retryable_errors() {
+        let api_error = |code| GeminiError::Api {
+            code,
+            message: String::new(),
+            status: None,
+            details: Vec::new(),
+        };
+        for (code, retryable) in [
+            (400, false),
+            (401, false),
+            (403, false),
+            (404, false),
+            (408, true),
+            (429, true),
+            (500, true),
+            (502, true),
+            (503, true),
+            (504, true),
+        ] {
+            assert_eq!(api_error(code).is_retryable(), retryable, "status code {}", code);
+        }
+        let interrupted = GeminiError::StreamInterrupted {
+            partial: String::new(),
+            message: "connection reset".into(),
+        };
+        assert!(interrupted.is_retryable());
+        assert!(!GeminiError::NoTextPart.is_retryable());
+        assert!(!GeminiError::ResponseTooLarge(1024).is_retryable());
+        // 非 JSON 的错误响应体保留状态码
+        let proxy_error = GeminiError::from_status_and_text(502, "<html><body>502 Bad Gateway</body></html>\n");
+        assert!(matches!(
+            &proxy_error,
+            GeminiError::Api { code: 502, message, .. } if message == "<html><body>502 Bad Gateway</body></html>"
+        ));
+        assert!(proxy_error.is_retryable());
+        assert!(is_retryable_error(&proxy_error.into()));
+        assert!(!is_retryable_error(
+            &GeminiError::from_status_and_text(400, "Bad Request").into()
+        ));
+        assert!(!is_retryable_error(&anyhow::anyhow!("invalid header value")));
+    }
+
+    #[tokio::test]
+    async fn classify_network_errors() -> Result<()> {
+        use tokio::net::TcpListener;
+
+        // 连接失败
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        drop(listener);
+        let error = Client::new()
+            .get(&url)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)
+            .unwrap_err();
+        assert!(is_retryable_error(&error));
+        // 请求超时
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_millis(100))
+            .build()?;
+        let error = client.get(&url).send().await.map_err(anyhow::Error::from).unwrap_err();
+        assert!(error
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout));
+        assert!(is_retryable_error(&error));
+        drop(listener);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_prompt_blocked() -> Result<()> {
+        let response: GenerateContentResponse = serde_json::from_str(
+            r#"{"promptFeedback":{"blockReason":"SAFETY","safetyRatings":[{"category":"HARM_CATEGORY_HARASSMENT","probability":"HIGH"},{"category":"HARM_CATEGORY_HATE_SPEECH","probability":"NEGLIGIBLE"}]}}"#,
+        )?;
+        let error = response.check_prompt_blocked().unwrap_err();
+        let Some(GeminiError::PromptBlocked {
+            block_reason,
+            safety_ratings,
+        }) = error.downcast_ref()
+        else {
+            panic!("expected a prompt blocked error");
+        };
+        assert!(matches!(block_reason, BlockReason::Safety));
+        assert!(matches!(safety_ratings[0].probability, HarmProbability::High));
+        Ok(())
+    }
+
+    #[test]
+    fn redact_api_key() {
+        use utils::redact::redact_key;
+
+        assert_eq!(
+            redact_key("error sending request for url (https://example.com/v1beta/models?key=AIzaSy-secret&alt=sse)"),
+            "error sending request for url (https://example.com/v1beta/models?key=***&alt=sse)"
+        );
+        assert_eq!(
+            redact_key("https://example.com/?key=secret"),
+            "https://example.com/?key=***"
+        );
+        assert_eq!(redact_key("no secrets here"), "no secrets here");
+        let client = model::Gemini::new("AIzaSy-secret".into(), param::LanguageModel::Gemini1_5Flash);
+        assert!(!format!("{:?}", client).contains("secret"));
+    }
+
+    #[test]
+    fn response_json_schema_is_exclusive() -> Result<()> {
+        let mut config = GenerationConfig::default();
+        config.set_response_json_schema(serde_json::json!({"type": "array", "items": {"type": "string"}}))?;
+        let config_json = serde_json::to_string(&config)?;
+        assert!(config_json.contains(r#""responseMimeType":"application/json""#));
+        assert!(config_json.contains(r#""responseJsonSchema":{"items":{"type":"string"},"type":"array"}"#));
+        config.response_schema = Some(Schema {
+            type0: Type::String,
+            format: None,
+            description: None,
+            nullable: None,
+            enum0: None,
+            max_items: None,
+            properties: None,
+            required: None,
+            items: None,
+        });
+        assert!(config.validate().is_err());
+        assert!(config.set_response_json_schema(serde_json::json!({})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn diff_generation_configs() {
+        let a = GenerationConfig::default();
+        assert!(a.diff(&a.clone()).is_empty());
+        let b = GenerationConfig {
+            temperature: Some(0.2),
+            top_k: Some(40),
+            ..Default::default()
+        };
+        assert_eq!(
+            a.diff(&b),
+            vec![
+                ("temperature", "1.0".to_owned(), "0.2".to_owned()),
+                ("top_k", "null".to_owned(), "40".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_response_to_text() -> Result<()> {
+        let mut client = model::Gemini::new("".into(), param::LanguageModel::Gemini1_5Flash);
+        client
+            .options
+            .set_response_json_schema(serde_json::json!({"type": "string"}))?;
+        client.set_response_text();
+        assert_eq!(client.options.response_mime_type.as_deref(), Some("text/plain"));
+        assert!(client.options.response_json_schema.is_none());
+        assert!(client.options.response_schema.is_none());
+        assert_eq!(client.options, GenerationConfig::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn system_instruction_in_request_body() -> Result<()> {
+        let client = model::Gemini::with_system_instruction(
+            "".into(),
+            param::LanguageModel::Gemini1_5Flash,
+            "你是 Reine".into(),
+        );
+        let body = client
+            .build_request_body(vec![(Role::User, "你是谁？".to_owned()).into()])
+            .await?;
+        let body_json = serde_json::to_value(&body)?;
+        assert_eq!(body_json["systemInstruction"]["parts"][0]["text"], "你是 Reine");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_safety_settings() -> Result<()> {
+        let settings = SafetySettings::new()
+            .all(HarmBlockThreshold::BlockNone)
+            .harassment(HarmBlockThreshold::BlockOnlyHigh)
+            .build();
+        assert_eq!(settings.len(), 4);
+        assert_eq!(
+            settings[0],
+            SafetySetting {
+                category: HarmCategory::HarmCategoryHarassment,
+                threshold: HarmBlockThreshold::BlockOnlyHigh,
+            }
+        );
+        let mut client = model::Gemini::new("".into(), param::LanguageModel::Gemini1_5Flash);
+        client.set_safety_settings(SafetySettings::new().hate_speech(HarmBlockThreshold::BlockLowAndAbove));
+        let body = client
+            .build_request_body(vec![(Role::User, "你是谁？".to_owned()).into()])
+            .await?;
+        let body_json = serde_json::to_value(&body)?;
+        assert_eq!(
+            body_json["safetySettings"],
+            serde_json::json!([{"category": "HARM_CATEGORY_HATE_SPEECH", "threshold": "BLOCK_LOW_AND_ABOVE"}])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cached_content_bound_to_model() -> Result<()> {
+        let cache: CachedContent = serde_json::from_str(
+            r#"{"name":"cachedContents/abc-123","model":"models/gemini-1.5-flash-001","displayName":"novel","expireTime":"2024-09-01T00:00:00Z","usageMetadata":{"totalTokenCount":40000}}"#,
+        )?;
+        let mut client = model::Gemini::new("".into(), param::LanguageModel::Custom("gemini-1.5-flash-001".into()));
+        client.set_cached_content(Some(cache));
+        let body = client.build_request_body(Vec::new()).await?;
+        assert_eq!(body.cached_content.as_deref(), Some("cachedContents/abc-123"));
+        client.set_model(param::LanguageModel::Gemini1_5Pro)?;
+        let err = client.build_request_body(Vec::new()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(GeminiError::CachedContentModelMismatch { cached_model, .. }) if cached_model == "models/gemini-1.5-flash-001"
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "current_date")]
+    #[tokio::test]
+    async fn inject_current_date_into_system_instruction() -> Result<()> {
+        let mut client = model::Gemini::new("".into(), param::LanguageModel::Gemini1_5Flash);
+        client.set_system_instruction("你是 Reine".into());
+        client.set_inject_current_date(true);
+        let body = client.build_request_body(Vec::new()).await?;
+        let body_json = serde_json::to_value(&body)?;
+        let today = chrono::Local::now().format("%Y-%m-%d");
+        assert_eq!(
+            body_json["systemInstruction"]["parts"][0]["text"],
+            format!("你是 Reine\n\nToday is {}.", today)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn response_language_keeps_system_instruction() -> Result<()> {
+        let mut client = model::Gemini::new("".into(), param::LanguageModel::Gemini1_5Flash);
+        client.set_response_language("简体中文".into());
+        let body = client.build_request_body(Vec::new()).await?;
+        let body_json = serde_json::to_value(&body)?;
+        assert_eq!(
+            body_json["systemInstruction"]["parts"][0]["text"],
+            "Always respond in 简体中文."
+        );
+        client.set_system_instruction("你是 Reine".into());
+        let body = client.build_request_body(Vec::new()).await?;
+        let body_json = serde_json::to_value(&body)?;
+        assert_eq!(
+            body_json["systemInstruction"]["parts"][0]["text"],
+            "你是 Reine\n\nAlways respond in 简体中文."
         );
         Ok(())
     }
@@ -62,6 +926,64 @@ mod tests {
         assert!(!models.is_empty());
     }
 
+    #[test]
+    fn normalize_custom_model() {
+        use param::LanguageModel;
+
+        let model = LanguageModel::Custom("gemini-2.0-flash".into());
+        assert_eq!(model.resource_name(), "models/gemini-2.0-flash");
+        assert_eq!(
+            model.normalize(),
+            LanguageModel::Custom("models/gemini-2.0-flash".into())
+        );
+        assert_eq!(
+            LanguageModel::Custom("gemini-1.5-flash".into()).normalize(),
+            LanguageModel::Gemini1_5Flash
+        );
+        assert_eq!(LanguageModel::Gemini1_5Pro.resource_name(), "models/gemini-1.5-pro");
+        assert_eq!(
+            LanguageModel::Custom("tunedModels/my-model".into()).resource_name(),
+            "tunedModels/my-model"
+        );
+    }
+
+    #[test]
+    fn parse_model_version() -> Result<()> {
+        let model: Model = serde_json::from_str(
+            r#"{"name":"models/gemini-1.5-flash-001","version":"001","displayName":"Gemini 1.5 Flash","description":"","inputTokenLimit":1000000,"outputTokenLimit":8192,"supportedGenerationMethods":["generateContent"]}"#,
+        )?;
+        let version = model.parse_version().unwrap();
+        assert_eq!(version.family, "gemini");
+        assert_eq!(version.major_minor(), (1, 5));
+        assert_eq!(version.variant.as_deref(), Some("flash"));
+        assert!(version.major_minor() >= (1, 5));
+        let model = Model {
+            name: "models/gemini-pro".into(),
+            ..model
+        };
+        assert!(model.parse_version().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn look_up_model_catalog() -> Result<()> {
+        let models: ModelsResponse = serde_json::from_str(
+            r#"{"models":[{"name":"models/gemini-1.5-flash","version":"001","displayName":"Gemini 1.5 Flash","description":"","inputTokenLimit":1000000,"outputTokenLimit":8192,"supportedGenerationMethods":["generateContent","countTokens"]},{"name":"models/text-embedding-004","version":"004","displayName":"Text Embedding 004","description":"","inputTokenLimit":2048,"outputTokenLimit":1,"supportedGenerationMethods":["embedContent"]}]}"#,
+        )?;
+        let catalog = ModelCatalog {
+            key: String::new(),
+            models: models.models,
+        };
+        assert_eq!(catalog.all().len(), 2);
+        assert_eq!(catalog.get("gemini-1.5-flash").unwrap().version, "001");
+        assert!(catalog.get("models/text-embedding-004").is_some());
+        assert!(catalog.get("gemini-1.5-pro").is_none());
+        let embedding = catalog.supporting("embedContent");
+        assert_eq!(embedding.len(), 1);
+        assert_eq!(embedding[0].name, "models/text-embedding-004");
+        Ok(())
+    }
+
     #[test]
     fn test_enum_serialize() {
         #[derive(Serialize, Deserialize)]
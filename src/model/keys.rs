@@ -0,0 +1,96 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// 触发限流的 key 的冷却时间
+const KEY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// API key 池
+/// 轮流使用多个 key，触发限流的 key 冷却一段时间后才会再次使用
+#[derive(Clone, Debug, Default)]
+pub(crate) struct KeyPool {
+    inner: Arc<Mutex<KeyPoolInner>>,
+}
+
+#[derive(Debug, Default)]
+struct KeyPoolInner {
+    keys: Vec<String>,
+    cooldowns: Vec<Option<Instant>>,
+    next: usize,
+}
+
+impl KeyPool {
+    /// 创建新实例
+    pub(crate) fn new(keys: Vec<String>) -> Self {
+        let cooldowns = vec![None; keys.len()];
+        Self {
+            inner: Arc::new(Mutex::new(KeyPoolInner {
+                keys,
+                cooldowns,
+                next: 0,
+            })),
+        }
+    }
+
+    /// key 的数量
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().unwrap().keys.len()
+    }
+
+    /// 未在冷却中的 key 的数量
+    pub(crate) fn active_count(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .cooldowns
+            .iter()
+            .filter(|cooldown| cooldown.is_none_or(|until| until <= now))
+            .count()
+    }
+
+    /// 按顺序取下一个未在冷却中的 key，全部在冷却中时取最早结束冷却的 key
+    pub(crate) fn next(&self) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.keys.len();
+        if len == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let index = (0..len)
+            .map(|offset| (inner.next + offset) % len)
+            .find(|&i| inner.cooldowns[i].is_none_or(|until| until <= now))
+            .unwrap_or_else(|| (0..len).min_by_key(|&i| inner.cooldowns[i]).unwrap_or(0));
+        inner.next = (index + 1) % len;
+        Some(inner.keys[index].clone())
+    }
+
+    /// 将触发限流的 key 标记为冷却中
+    pub(crate) fn cool_down(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(index) = inner.keys.iter().position(|k| k == key) {
+            inner.cooldowns[index] = Some(Instant::now() + KEY_COOLDOWN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_and_cool_down_keys() {
+        let pool = KeyPool::new(vec!["a".into(), "b".into(), "c".into()]);
+        assert_eq!(pool.next().as_deref(), Some("a"));
+        assert_eq!(pool.next().as_deref(), Some("b"));
+        pool.cool_down("c");
+        assert_eq!(pool.active_count(), 2);
+        assert_eq!(pool.next().as_deref(), Some("a"));
+        pool.cool_down("a");
+        pool.cool_down("b");
+        assert_eq!(pool.active_count(), 0);
+        // 全部在冷却中时取最早结束冷却的 key
+        assert_eq!(pool.next().as_deref(), Some("c"));
+        assert_eq!(KeyPool::default().next(), None);
+    }
+}
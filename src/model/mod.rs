@@ -1,22 +1,419 @@
 #[cfg(feature = "blocking")]
 pub mod blocking;
+mod keys;
+pub mod transport;
 
-use anyhow::{bail, Result};
-use reqwest::Client;
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::Path,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, REFERER},
+    Client, Response, StatusCode,
+};
+use serde::{Deserialize, Deserializer};
 use serde_json;
+use tokio::{io::AsyncReadExt, sync::mpsc};
 
 use crate::{
     body::{
-        error::GenerateContentResponseError,
-        request::{GeminiRequestBody, GenerationConfig},
-        response::GenerateContentResponse,
-        Content, Part, Role,
+        error::GeminiError,
+        request::{
+            BatchEmbedContentsRequest, CountTokensRequest, CreateCachedContentRequest, EmbedContentRequest,
+            GeminiRequestBody, GenerateAnswerRequest, GenerationConfig, SafetySetting, Tool,
+        },
+        response::{
+            BatchEmbedContentsResponse, CachedContent, Candidate, CountTokensResponse, File, FinishReason,
+            GenerateAnswerResponse, GenerateContentResponse, Model, ModelsResponse, UploadFileResponse,
+        },
+        Content, FunctionCall, FunctionResponse, Part, Role, VideoMetadata,
     },
-    param::LanguageModel,
+    param::{ExtractStrategy, LanguageModel},
+    utils::{mime::guess_mime, redact::redact_error, sse::parse_sse_chunk, stream::SentenceSplitter},
 };
 
+use keys::KeyPool;
+use transport::{HttpResponse, HttpTransport};
+
 pub const GEMINI_API_URL: &str = "https://generativelanguage.googleapis.com/v1beta/";
 
+/// Files API 上传文件后返回的 URI 前缀
+pub const GEMINI_FILES_URL: &str = "https://generativelanguage.googleapis.com/v1beta/files/";
+
+/// Files API 断点续传上传地址
+pub const GEMINI_UPLOAD_URL: &str = "https://generativelanguage.googleapis.com/upload/v1beta/files";
+
+/// 缓存系统指令时缓存内容的有效期
+const SYSTEM_INSTRUCTION_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// 续传中断的流式响应时发送给模型的指令
+const RESUME_PROMPT: &str = "Continue exactly from where your previous response was cut off, without repeating it.";
+
+/// 批量计算向量表示时每次请求的最大文本条数
+const EMBED_BATCH_SIZE: usize = 100;
+
+/// 断点续传每块上传的字节数，需为 256 KiB 的整数倍
+const UPLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Vertex AI 的项目与区域，请求发送到对应区域的 Vertex AI 地址并使用 Bearer token 认证
+#[derive(Clone, Debug)]
+struct VertexEndpoint {
+    project: String,
+    location: String,
+}
+
+impl VertexEndpoint {
+    /// 项目与区域下的接口地址，如 `https://us-central1-aiplatform.googleapis.com/v1/projects/p/locations/us-central1/`
+    fn api_url(&self) -> String {
+        format!(
+            "https://{location}-aiplatform.googleapis.com/v1/projects/{project}/locations/{location}/",
+            project = self.project,
+            location = self.location
+        )
+    }
+}
+
+/// 模型的请求地址，api_url 为接口地址，Vertex AI 下为 `{项目与区域}/publishers/google/models/{模型}`
+fn model_url(api_url: &str, vertex: bool, model: &LanguageModel) -> String {
+    if vertex {
+        format!("{}publishers/google/{}", api_url, model.resource_name())
+    } else {
+        format!("{}{}", api_url, model.resource_name())
+    }
+}
+
+/// 客户端配置，可从 TOML、JSON 等配置文件反序列化后通过 `Gemini::from_config` 一次性创建实例
+/// model 为模型名称，如 "gemini-1.5-flash"；timeout 为整个请求的超时秒数；base_url 用于替换默认的接口地址，如使用代理网关
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GeminiConfig {
+    pub key: String,
+    #[serde(deserialize_with = "deserialize_model")]
+    pub model: LanguageModel,
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_secs")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub system_instruction: Option<String>,
+    #[serde(default)]
+    pub options: GenerationConfig,
+}
+
+impl GeminiConfig {
+    /// 配置的接口地址，补全末尾的 `/`
+    fn api_url(&self) -> Option<String> {
+        self.base_url.as_ref().map(|url| match url.ends_with('/') {
+            true => url.clone(),
+            false => format!("{}/", url),
+        })
+    }
+}
+
+/// 将模型名称反序列化为对应的模型，名称可带或不带 "models/" 前缀
+fn deserialize_model<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<LanguageModel, D::Error> {
+    Ok(LanguageModel::from(String::deserialize(deserializer)?).normalize())
+}
+
+/// 将秒数反序列化为时长，可带小数
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// 为请求附加认证信息，Vertex AI 在请求头中附带 Bearer token，否则在地址中附带 API key
+fn authorize(url: &str, key: &str, headers: &HeaderMap, vertex: bool) -> Result<(String, HeaderMap)> {
+    let mut headers = headers.clone();
+    if vertex {
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", key))?);
+        return Ok((url.to_owned(), headers));
+    }
+    let separator = if url.contains('?') { '&' } else { '?' };
+    Ok((format!("{}{}key={}", url, separator, key), headers))
+}
+
+/// 单次请求的大小上限，内联数据超过该大小时需改用 Files API 上传
+#[cfg(feature = "image_analysis")]
+const INLINE_DATA_LIMIT: usize = 20 * 1024 * 1024;
+
+/// 校验所有内联数据（base64 编码后）的总大小未超过请求大小上限
+#[cfg(feature = "image_analysis")]
+fn check_inline_data_size(contents: &[Content]) -> Result<()> {
+    let size: usize = contents
+        .iter()
+        .flat_map(|content| &content.parts)
+        .map(|part| match part {
+            Part::InlineData { data, .. } => data.len(),
+            _ => 0,
+        })
+        .sum();
+    if size > INLINE_DATA_LIMIT {
+        bail!(
+            "Inline data is {} bytes, exceeding the request limit of {} bytes, upload the file via the Files API instead",
+            size,
+            INLINE_DATA_LIMIT
+        );
+    }
+    Ok(())
+}
+
+/// 校验文件 URI 是否为 Files API 返回的 URI
+fn check_file_uri(file_uri: &str) -> Result<()> {
+    match file_uri.strip_prefix(GEMINI_FILES_URL) {
+        Some(name) if !name.is_empty() && !name.contains('/') => Ok(()),
+        _ => bail!(
+            "Invalid file uri: {}, expected a Files API uri like {}abc-123",
+            file_uri,
+            GEMINI_FILES_URL
+        ),
+    }
+}
+
+/// 回复是否为空，即第一个候选正常结束但只包含空白文本
+/// 函数调用等非文本内容以及因安全等原因结束的回复不视为空
+fn is_empty_response(response: &GenerateContentResponse) -> bool {
+    let Some(candidate) = response.candidates.first() else {
+        return false;
+    };
+    matches!(candidate.finish_reason, None | Some(FinishReason::Stop))
+        && candidate
+            .content
+            .parts
+            .iter()
+            .all(|part| matches!(part, Part::Text(text) if text.trim().is_empty()))
+}
+
+/// 将系统指令与历史记录转换为 OpenAI 格式的消息，即 `[{"role": ..., "content": ...}]`
+/// 系统指令作为第一条 system 消息，模型回复的角色为 assistant，每条消息的文本部分拼接为 content，不含文本的消息被跳过
+fn to_openai_messages(system_instruction: Option<String>, contents: &[Content]) -> Vec<serde_json::Value> {
+    let system = system_instruction.map(|instruction| serde_json::json!({"role": "system", "content": instruction}));
+    let messages = contents.iter().filter_map(|content| {
+        let role = match content.role {
+            Some(Role::Model) => "assistant",
+            Some(Role::System) => "system",
+            Some(Role::User) | None => "user",
+            Some(Role::Function) => return None,
+        };
+        let text = content.text();
+        (!text.is_empty()).then(|| serde_json::json!({"role": role, "content": text}))
+    });
+    system.into_iter().chain(messages).collect()
+}
+
+/// 解析 OpenAI 格式的消息，返回拼接后的系统指令以及历史记录
+/// content 可为字符串或包含文本部分的数组，非文本部分被忽略；tool、function 消息以及没有文本的消息被跳过
+fn from_openai_messages(messages: &[serde_json::Value]) -> Result<(Option<String>, Vec<Content>)> {
+    let mut system = Vec::new();
+    let mut contents = Vec::new();
+    for message in messages {
+        let text = match &message["content"] {
+            serde_json::Value::String(text) => text.clone(),
+            serde_json::Value::Array(parts) => parts
+                .iter()
+                .filter(|part| part["type"] == "text")
+                .filter_map(|part| part["text"].as_str())
+                .collect(),
+            _ => String::new(),
+        };
+        let role = match message["role"].as_str() {
+            Some("user") => Role::User,
+            Some("assistant") => Role::Model,
+            Some("system") => {
+                system.push(text);
+                continue;
+            }
+            Some("tool" | "function") => continue,
+            _ => bail!("Unsupported role of OpenAI message: {}", message["role"]),
+        };
+        if !text.is_empty() {
+            contents.push((role, text).into());
+        }
+    }
+    let system_instruction = (!system.is_empty()).then(|| system.join("\n\n"));
+    Ok((system_instruction, contents))
+}
+
+/// 校验模型是否在允许列表中
+fn check_model_allowed(model: &LanguageModel, allowed: &[LanguageModel]) -> Result<()> {
+    // 按资源名称比较，使 Custom("gemini-1.5-flash") 与 Gemini1_5Flash 视为同一模型
+    if allowed.iter().any(|m| m.resource_name() == model.resource_name()) {
+        Ok(())
+    } else {
+        bail!(GeminiError::ModelNotAllowed(model.to_string()))
+    }
+}
+
+/// 使用同一提示词分别请求两个模型，返回两个模型的回复文本
+/// 两次请求均为不带历史记录的单次请求，并发发送
+pub async fn compare_models(
+    key: String,
+    prompt: String,
+    model_a: LanguageModel,
+    model_b: LanguageModel,
+) -> Result<(String, String)> {
+    let client_a = Gemini::new(key.clone(), model_a);
+    let client_b = Gemini::new(key, model_b);
+    let (text_a, text_b) = tokio::try_join!(client_a.generate_text(prompt.clone()), client_b.generate_text(prompt))?;
+    Ok((text_a, text_b))
+}
+
+/// 列出全部可用模型，自动请求全部分页
+pub async fn list_models(key: String) -> Result<Vec<Model>> {
+    let client = Client::new();
+    let mut models = Vec::new();
+    let mut page_token = None;
+    loop {
+        let url = models_page_url(&key, page_token.as_deref());
+        let response = client.get(url).send().await.map_err(redact_error)?;
+        let status = response.status();
+        let response_text = response.text().await.map_err(redact_error)?;
+        if !status.is_success() {
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+        let page: ModelsResponse = serde_json::from_str(&response_text)?;
+        models.extend(page.models);
+        page_token = page.next_page_token.filter(|token| !token.is_empty());
+        if page_token.is_none() {
+            return Ok(models);
+        }
+    }
+}
+
+/// 列出支持 generateContent 的模型及其展示名称，可直接用于下拉框等界面
+/// 模型名称通过 `LanguageModel::from` 转换，未知的模型转换为 `LanguageModel::Custom`
+pub async fn list_model_choices(key: String) -> Result<Vec<(LanguageModel, String)>> {
+    Ok(model_choices(list_models(key).await?))
+}
+
+/// 列出模型时每页请求的最大数量
+const MODELS_PAGE_SIZE: usize = 1000;
+
+/// 列出模型的请求地址，page_token 为上一页返回的分页标记
+fn models_page_url(key: &str, page_token: Option<&str>) -> String {
+    let mut url = format!("{}models?pageSize={}&key={}", GEMINI_API_URL, MODELS_PAGE_SIZE, key);
+    if let Some(page_token) = page_token {
+        url.push_str("&pageToken=");
+        url.push_str(page_token);
+    }
+    url
+}
+
+/// 过滤出支持 generateContent 的模型，并与其展示名称配对
+fn model_choices(models: Vec<Model>) -> Vec<(LanguageModel, String)> {
+    models
+        .into_iter()
+        .filter(|model| {
+            model
+                .supported_generation_methods
+                .iter()
+                .any(|m| m == "generateContent")
+        })
+        .map(|model| (LanguageModel::from(model.name), model.display_name))
+        .collect()
+}
+
+/// 模型回复开头可能多余输出的角色标签
+const ROLE_PREFIXES: [&str; 4] = ["Model:", "model:", "Assistant:", "assistant:"];
+
+/// 去掉文本开头的角色标签以及紧随其后的空白
+fn strip_role_prefix(text: &str) -> &str {
+    ROLE_PREFIXES
+        .iter()
+        .find_map(|prefix| text.strip_prefix(prefix))
+        .map_or(text, str::trim_start)
+}
+
+/// 解析响应内容，提示词被拦截时返回 `GeminiError::PromptBlocked`
+/// 解析失败时返回携带原始响应体的 `GeminiError::InvalidResponse`
+/// 宽松模式下忽略开头的 BOM 以及第一个 JSON 值之后的多余内容，以兼容不完全规范的代理网关
+fn parse_response(response_text: &str, lenient: bool) -> Result<GenerateContentResponse> {
+    let invalid = |e: serde_json::Error| GeminiError::InvalidResponse {
+        raw: response_text.to_owned(),
+        message: e.to_string(),
+    };
+    let response: GenerateContentResponse = if lenient {
+        serde_json::Deserializer::from_str(response_text.trim_start_matches('\u{feff}'))
+            .into_iter()
+            .next()
+            // 响应体为空时返回与严格模式相同的 EOF 错误
+            .unwrap_or_else(|| serde_json::from_str(""))
+            .map_err(invalid)?
+    } else {
+        serde_json::from_str(response_text).map_err(invalid)?
+    };
+    response.check_prompt_blocked()?;
+    Ok(response)
+}
+
+/// 识别上传文件的媒体类型时读取的文件开头字节数
+const MIME_SNIFF_LEN: u64 = 64;
+
+/// 根据文件开头的内容与扩展名识别上传文件的媒体类型，无法识别时返回错误
+fn detect_mime_type(path: &str, head: &[u8]) -> Result<String> {
+    let extension = Path::new(path).extension().and_then(|extension| extension.to_str());
+    guess_mime(head, extension).ok_or_else(|| anyhow!("Failed to detect the MIME type of {}", path))
+}
+
+/// 校验历史记录中模型的消息与用户（包括函数调用结果）的消息交替出现
+fn check_role_alternation(contents: &[Content]) -> Result<()> {
+    let is_model = |content: &Content| content.role == Some(Role::Model);
+    if let Some(i) = contents
+        .windows(2)
+        .position(|pair| is_model(&pair[0]) == is_model(&pair[1]))
+    {
+        bail!(
+            "Roles of the history must alternate, but messages {} and {} are both from the same side",
+            i,
+            i + 1
+        )
+    }
+    Ok(())
+}
+
+/// 将模型提供的 temperature、top_p、top_k 应用到生成配置中，模型未提供的参数保持不变
+fn apply_model_defaults(options: &mut GenerationConfig, model: &Model) {
+    if let Some(temperature) = model.temperature {
+        options.temperature = Some(temperature);
+    }
+    if let Some(top_p) = model.top_p {
+        options.top_p = Some(top_p);
+    }
+    if let Some(top_k) = model.top_k {
+        options.top_k = Some(top_k);
+    }
+}
+
+/// 配置的最大输出 token 数与模型上限中较小的一个，未配置时为模型上限
+fn effective_output_limit(max_output_tokens: Option<isize>, output_token_limit: isize) -> isize {
+    max_output_tokens.map_or(output_token_limit, |max| max.min(output_token_limit))
+}
+
+/// 获取第一个候选回复的第一部分内容
+/// 候选回复没有内容（例如只有结束原因）时返回空文本，结束原因可从响应中读取
+fn first_part(response: &GenerateContentResponse) -> Result<Part> {
+    let Some(candidate) = response.candidates.first() else {
+        bail!(GeminiError::NoTextPart)
+    };
+    Ok(candidate
+        .content
+        .parts
+        .first()
+        .cloned()
+        .unwrap_or_else(|| Part::Text(String::new())))
+}
+
 #[derive(Clone, Default)]
 pub struct Gemini {
     pub key: String,
@@ -25,8 +422,51 @@ pub struct Gemini {
     pub options: GenerationConfig,
     pub system_instruction: Option<String>,
     pub conversation: bool,
-    url: String,
     client: Client,
+    tcp_keepalive: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    base_url: Option<String>,
+    allowed_models: Option<Vec<LanguageModel>>,
+    keys: KeyPool,
+    response_language: Option<String>,
+    safety_settings: Option<Vec<SafetySetting>>,
+    #[cfg(feature = "current_date")]
+    inject_current_date: bool,
+    last_finish_reason: Option<FinishReason>,
+    last_candidates: Option<Vec<Candidate>>,
+    headers: HeaderMap,
+    stream_reconnects: usize,
+    stream_continuations: usize,
+    retry_on_empty: Option<usize>,
+    auto_trim_tokens: Option<isize>,
+    strip_role_prefix: bool,
+    image_download_retries: usize,
+    lenient_parsing: bool,
+    extract_strategy: ExtractStrategy,
+    text_join_separator: String,
+    max_response_bytes: Option<usize>,
+    keep_failed_turns: bool,
+    cached_content: Option<CachedContent>,
+    system_instruction_cached: bool,
+    transport: Option<Arc<dyn HttpTransport>>,
+    vertex: Option<VertexEndpoint>,
+}
+
+/// 打印调试信息时隐藏 API key
+impl fmt::Debug for Gemini {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gemini")
+            .field("key", &"***")
+            .field("model", &self.model)
+            .field("contents", &self.contents)
+            .field("options", &self.options)
+            .field("system_instruction", &self.system_instruction)
+            .field("conversation", &self.conversation)
+            .field("response_language", &self.response_language)
+            .field("vertex", &self.vertex)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Gemini {
@@ -34,17 +474,130 @@ impl Gemini {
     pub fn new(key: String, model: LanguageModel) -> Self {
         let client = Client::new();
         let contents = Vec::new();
-        let url = format!("{}{}:generateContent", GEMINI_API_URL, model);
         Self {
             key,
             model,
             contents,
-            url,
             client,
             ..Default::default()
         }
     }
 
+    /// 创建不校验 TLS 证书的新实例，仅用于开发环境中使用自签名证书的本地代理
+    /// 警告：会接受任意证书，使连接容易遭受中间人攻击，切勿在生产环境中使用
+    #[cfg(feature = "dangerous-tls")]
+    pub fn with_danger_accept_invalid_certs(key: String, model: LanguageModel) -> Result<Self> {
+        let client = Client::builder().danger_accept_invalid_certs(true).build()?;
+        Ok(Self {
+            client,
+            ..Self::new(key, model)
+        })
+    }
+
+    /// 根据配置创建新实例，可用于从配置文件一次性加载全部客户端配置
+    /// 配置了 base_url 时，模型方法与缓存内容的请求发送到该地址，Files API 的请求不受影响
+    pub fn from_config(config: GeminiConfig) -> Result<Self> {
+        let base_url = config.api_url();
+        let mut client = Self {
+            system_instruction: config.system_instruction,
+            options: config.options,
+            timeout: config.timeout,
+            base_url,
+            ..Self::new(config.key, config.model)
+        };
+        if client.timeout.is_some() {
+            client.client = client.build_client()?;
+        }
+        Ok(client)
+    }
+
+    /// 开启 TCP keepalive，每隔 interval 发送探测包，避免模型长时间思考时空闲的流式连接被网络设备断开，可链式调用
+    /// 会替换内部的 HTTP 客户端，此前通过 `with_danger_accept_invalid_certs` 等方式创建的客户端配置不再保留
+    pub fn with_tcp_keepalive(mut self, interval: Duration) -> Result<Self> {
+        self.tcp_keepalive = Some(interval);
+        self.client = self.build_client()?;
+        Ok(self)
+    }
+
+    /// 配置建立连接的超时时间，与整个请求的耗时无关，可在网络不可达时尽快失败，同时不限制连接后的长时间生成，可链式调用
+    /// 与 `with_tcp_keepalive` 一样会替换内部的 HTTP 客户端，两者的配置可同时生效
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.connect_timeout = Some(timeout);
+        self.client = self.build_client()?;
+        Ok(self)
+    }
+
+    /// 按配置的 TCP keepalive、连接超时时间与请求超时时间构建 HTTP 客户端
+    fn build_client(&self) -> Result<Client> {
+        let mut builder = Client::builder();
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(interval);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// 创建使用 Vertex AI 的新实例
+    /// 请求发送到 `{location}-aiplatform.googleapis.com` 下对应项目与区域的模型，使用 OAuth access token 作为 Bearer token 认证
+    pub fn vertex(project: String, location: String, model: LanguageModel, token: String) -> Self {
+        Self {
+            vertex: Some(VertexEndpoint { project, location }),
+            ..Self::new(token, model)
+        }
+    }
+
+    /// 使用多个 API key 创建新实例
+    /// 每次请求轮流使用其中一个 key，某个 key 触发 429 限流后会冷却一段时间，期间换用其他 key
+    pub fn with_keys(keys: Vec<String>, model: LanguageModel) -> Self {
+        let key = keys.first().cloned().unwrap_or_default();
+        Self {
+            keys: KeyPool::new(keys),
+            ..Self::new(key, model)
+        }
+    }
+
+    /// 当前可用（未在冷却中）的 API key 数量
+    pub fn active_key_count(&self) -> usize {
+        if self.keys.len() == 0 {
+            1
+        } else {
+            self.keys.active_count()
+        }
+    }
+
+    /// 创建只允许使用指定模型的实例
+    /// 模型不在允许列表中时返回 `GeminiError::ModelNotAllowed`
+    pub fn with_allowed_models(key: String, model: LanguageModel, allowed: Vec<LanguageModel>) -> Result<Self> {
+        check_model_allowed(&model, &allowed)?;
+        Ok(Self {
+            allowed_models: Some(allowed),
+            ..Self::new(key, model)
+        })
+    }
+
+    /// 切换模型
+    /// 配置了允许列表时，切换到不在列表中的模型返回 `GeminiError::ModelNotAllowed`
+    pub fn set_model(&mut self, model: LanguageModel) -> Result<()> {
+        if let Some(allowed) = &self.allowed_models {
+            check_model_allowed(&model, allowed)?;
+        }
+        self.model = model;
+        Ok(())
+    }
+
+    /// 创建带有系统指令的新实例
+    pub fn with_system_instruction(key: String, model: LanguageModel, instruction: String) -> Self {
+        Self {
+            system_instruction: Some(instruction),
+            ..Self::new(key, model)
+        }
+    }
+
     /// 配置系统指令
     pub fn set_system_instruction(&mut self, instruction: String) {
         self.system_instruction = Some(instruction);
@@ -53,70 +606,523 @@ impl Gemini {
     /// 重建实例
     pub fn rebuild(key: String, model: LanguageModel, contents: Vec<Content>, options: GenerationConfig) -> Self {
         let client = Client::new();
-        let url = format!("{}{}:generateContent", GEMINI_API_URL, model);
         Self {
             key,
             model,
             contents,
             options,
-            url,
             client,
             conversation: true,
             ..Default::default()
         }
     }
 
+    /// 配置回复语言，如 "简体中文"、"English"
+    /// 没有专门的 API 字段，构建请求时会在系统指令末尾追加语言要求，不会覆盖已配置的系统指令
+    pub fn set_response_language(&mut self, lang: String) {
+        self.response_language = Some(lang);
+    }
+
+    /// 配置是否在系统指令末尾追加当前日期，如 "Today is 2024-09-01."，默认不追加
+    /// 日期在构建请求时按本地时区获取，与已配置的系统指令以及回复语言要求合并，不会覆盖它们
+    #[cfg(feature = "current_date")]
+    pub fn set_inject_current_date(&mut self, inject: bool) {
+        self.inject_current_date = inject;
+    }
+
+    /// 获取最近的 n 条历史记录，不足 n 条时返回全部历史记录
+    pub fn recent(&self, n: usize) -> &[Content] {
+        &self.contents[self.contents.len().saturating_sub(n)..]
+    }
+
+    /// 获取历史记录条数
+    pub fn history_len(&self) -> usize {
+        self.contents.len()
+    }
+
+    /// 按轮次遍历历史记录，将每条用户消息（包括函数调用结果）与紧随其后的模型回复配对
+    /// 最后一条用户消息可能尚未得到回复，此时模型回复为 None，没有对应用户消息的模型回复会被跳过
+    pub fn turns(&self) -> impl Iterator<Item = (&Content, Option<&Content>)> {
+        let mut contents = self.contents.iter().peekable();
+        std::iter::from_fn(move || loop {
+            let content = contents.next()?;
+            if content.role == Some(Role::Model) {
+                continue;
+            }
+            let reply = contents.next_if(|next| next.role == Some(Role::Model));
+            return Some((content, reply));
+        })
+    }
+
+    /// 复制配置与历史记录创建一个新的分支，用于从当前对话分叉出不同的后续对话
+    /// 新分支不保留最近一次请求的结束原因与候选回复
+    pub fn fork(&self) -> Self {
+        Self {
+            last_finish_reason: None,
+            last_candidates: None,
+            ..self.clone()
+        }
+    }
+
+    /// 将另一个分支的历史记录追加到当前历史记录之后，两者开头相同的记录（如分叉前的对话）只保留一份
+    /// 合并后用户与模型的消息需交替出现，否则返回错误且不修改当前历史记录
+    pub fn merge_history(&mut self, other: &Self) -> Result<()> {
+        let common = self
+            .contents
+            .iter()
+            .zip(&other.contents)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut contents = self.contents.clone();
+        contents.extend_from_slice(&other.contents[common..]);
+        check_role_alternation(&contents)?;
+        self.contents = contents;
+        Ok(())
+    }
+
+    /// 获取最近一次请求返回的全部候选回复，可用于查看安全评级以及引用来源，尚未请求时返回 None
+    /// 流式请求不会更新该记录
+    pub fn last_candidates(&self) -> Option<&[Candidate]> {
+        self.last_candidates.as_deref()
+    }
+
+    /// 以 (角色, 文本) 的形式获取历史记录，跳过包含非文本内容的消息
+    pub fn history_as_tuples(&self) -> Vec<(Role, String)> {
+        self.contents.iter().filter_map(Content::as_role_text).collect()
+    }
+
+    /// 将历史记录导出为 OpenAI 格式的消息，便于复用 OpenAI 格式的日志与评估工具
+    /// 实际生效的系统指令作为第一条 system 消息，只导出文本内容，函数调用等不含文本的消息被跳过
+    pub fn to_openai_messages(&self) -> Vec<serde_json::Value> {
+        to_openai_messages(self.effective_system_instruction(), &self.contents)
+    }
+
+    /// 从 OpenAI 格式的消息导入历史记录并开启历史记录，可用于将已有的 OpenAI 对话记录迁移后继续对话
+    /// user 与 assistant 消息分别转换为用户消息与模型回复并替换当前历史记录，system 消息拼接后替换系统指令，
+    /// tool 与 function 消息被跳过，遇到其他角色时返回错误
+    pub fn from_openai_messages(&mut self, messages: &[serde_json::Value]) -> Result<()> {
+        let (system_instruction, contents) = from_openai_messages(messages)?;
+        if system_instruction.is_some() {
+            self.system_instruction = system_instruction;
+        }
+        self.start_chat(contents);
+        Ok(())
+    }
+
+    /// 配置每次请求附带的自定义请求头，如实验性请求头、链路追踪请求头
+    /// 其中的 Content-Type 与 x-goog-api-key 会被忽略，不会覆盖本库设置的请求头与鉴权方式
+    pub fn set_headers(&mut self, mut headers: HeaderMap) {
+        headers.remove(CONTENT_TYPE);
+        headers.remove("x-goog-api-key");
+        self.headers = headers;
+    }
+
+    /// 配置每次请求附带的 Referer 请求头，使限制了 HTTP 引荐来源的 API key 可在服务端使用，如用于测试
+    /// 未配置时此类 key 的请求会返回 403，之后调用 `set_headers` 会覆盖该配置
+    pub fn set_referer(&mut self, url: String) -> Result<()> {
+        self.headers.insert(REFERER, HeaderValue::from_str(&url)?);
+        Ok(())
+    }
+
+    /// 配置流式响应因网络问题中断时自动重连的最大次数，默认为 0 即不重连
+    /// 重连时连同已接收的文本重新发送历史记录，要求模型从中断处继续，重连后的第一个响应片段的
+    /// `GenerateContentResponse::reconnected` 为 true，续写内容可能与已接收的文本有重复
+    pub fn set_stream_reconnects(&mut self, max_reconnects: usize) {
+        self.stream_reconnects = max_reconnects;
+    }
+
+    /// 配置回复文本为空或仅含空白时重新发送的最大次数，默认不重试
+    /// 适用于 `send_message`、`send_simple_message` 与 `send_image_message`，
+    /// 重试后仍为空时返回 `GeminiError::EmptyResponse`，历史记录中不保留这次请求
+    pub fn set_retry_on_empty(&mut self, max_retries: usize) {
+        self.retry_on_empty = Some(max_retries);
+    }
+
+    /// 配置 `send_stream_collect` 在回复因达到最大输出 token 数被截断时自动续写的最大次数，默认为 0 即不续写
+    /// 续写时将已接收的文本作为模型回复的开头发送，续写的文本同样通过 on_chunk 回调产出
+    pub fn set_stream_auto_continue(&mut self, max_continuations: usize) {
+        self.stream_continuations = max_continuations;
+    }
+
+    /// 配置是否宽松解析响应，默认不开启
+    /// 开启后忽略响应体开头的 BOM 以及 JSON 之后的多余内容，用于兼容返回不完全规范响应的代理网关，未知字段始终会被忽略
+    pub fn set_lenient_parsing(&mut self, lenient: bool) {
+        self.lenient_parsing = lenient;
+    }
+
+    /// 配置下载网络图片失败时的最大重试次数，默认为 0 即不重试
+    /// 仅在网络错误或可重试的状态码（408、429、5xx）时按指数退避重试，与生成请求的重试相互独立
+    /// 等待时间从 500 毫秒开始每次翻倍，最长为 30 秒
+    pub fn set_image_download_retries(&mut self, retries: usize) {
+        self.image_download_retries = retries;
+    }
+
+    /// 配置是否去掉模型回复开头多余的角色标签，如 "Model:"，默认不去掉
+    /// 只去掉完全匹配的已知标签，不会改动其他内容
+    pub fn set_strip_role_prefix(&mut self, strip: bool) {
+        self.strip_role_prefix = strip;
+    }
+
+    /// 配置请求成功后返回文本的提取方式，默认拼接所有文本部分
+    pub fn set_extract_strategy(&mut self, strategy: ExtractStrategy) {
+        self.extract_strategy = strategy;
+    }
+
+    /// 配置拼接多个文本部分时使用的分隔符，默认为空，即直接拼接
+    /// 面向 markdown 等按行排版的场景可设置为 "\n"
+    pub fn set_text_join_separator(&mut self, sep: String) {
+        self.text_join_separator = sep;
+    }
+
+    /// 配置响应体的最大字节数，读取响应时超过上限则中止并返回 `GeminiError::ResponseTooLarge`
+    /// 对流式响应同样生效，按已接收的总字节数计算
+    pub fn set_max_response_bytes(&mut self, max_response_bytes: usize) {
+        self.max_response_bytes = Some(max_response_bytes);
+    }
+
+    /// 注入 HTTP 传输层，之后的请求都通过该传输层发送，例如在测试中返回固定的响应
+    pub fn set_transport(&mut self, transport: impl HttpTransport + 'static) {
+        self.transport = Some(Arc::new(transport));
+    }
+
+    /// 配置请求使用的缓存内容，传入 None 时不使用缓存
+    /// 缓存内容与模型绑定，请求的模型与缓存的模型不一致时返回 `GeminiError::CachedContentModelMismatch`
+    pub fn set_cached_content(&mut self, cached_content: Option<CachedContent>) {
+        self.cached_content = cached_content;
+        self.system_instruction_cached = false;
+    }
+
+    /// 配置请求失败时是否保留最后发送的那次用户请求，默认不保留
+    /// 保留时可在修正配置后调用 `regenerate` 重新请求
+    pub fn set_keep_failed_turns(&mut self, keep_failed_turns: bool) {
+        self.keep_failed_turns = keep_failed_turns;
+    }
+
     /// 参数配置
     pub fn set_options(&mut self, options: GenerationConfig) {
         self.options = options;
     }
 
+    /// 配置安全设置，可通过 `SafetySettings` 按类别构建，如 `SafetySettings::new().all(HarmBlockThreshold::BlockNone)`
+    /// 未配置的类别使用 API 的默认设置
+    pub fn set_safety_settings(&mut self, settings: impl Into<Vec<SafetySetting>>) {
+        self.safety_settings = Some(settings.into());
+    }
+
+    /// 切换回纯文本回复，将 responseMimeType 重置为 `text/plain` 并清除输出结构
+    /// 用于开启 JSON 模式后恢复普通文本请求，避免残留的 JSON 配置影响之后的请求
+    pub fn set_response_text(&mut self) {
+        self.options.set_response_text();
+    }
+
+    /// 配置 temperature，可链式调用，如 `Gemini::new(key, model).temperature(0.2).top_p(0.9)`
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.options.temperature = Some(temperature);
+        self
+    }
+
+    /// 配置 topP，可链式调用
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.options.top_p = Some(top_p);
+        self
+    }
+
+    /// 配置 topK，可链式调用
+    pub fn top_k(mut self, top_k: isize) -> Self {
+        self.options.top_k = Some(top_k);
+        self
+    }
+
+    /// 配置最大输出 token 数，可链式调用
+    pub fn max_output_tokens(mut self, max_output_tokens: isize) -> Self {
+        self.options.max_output_tokens = Some(max_output_tokens);
+        self
+    }
+
+    /// 构建模型方法的请求地址，如 generateContent、generateAnswer
+    fn method_url(&self, method: &str) -> String {
+        format!(
+            "{}:{}",
+            model_url(&self.api_url(), self.vertex.is_some(), &self.model),
+            method
+        )
+    }
+
+    /// 接口地址，使用 Vertex AI 时为项目与区域下的地址，配置了 base_url 时为配置的地址
+    fn api_url(&self) -> String {
+        match (&self.vertex, &self.base_url) {
+            (Some(vertex), _) => vertex.api_url(),
+            (None, Some(base_url)) => base_url.clone(),
+            (None, None) => GEMINI_API_URL.to_owned(),
+        }
+    }
+
+    /// 合并系统指令、回复语言要求以及当前日期
+    fn effective_system_instruction(&self) -> Option<String> {
+        let mut instructions: Vec<String> = self.system_instruction.iter().cloned().collect();
+        if let Some(lang) = &self.response_language {
+            instructions.push(format!("Always respond in {}.", lang));
+        }
+        #[cfg(feature = "current_date")]
+        if self.inject_current_date {
+            instructions.push(format!("Today is {}.", chrono::Local::now().format("%Y-%m-%d")));
+        }
+        (!instructions.is_empty()).then(|| instructions.join("\n\n"))
+    }
+
     /// 构建请求体
-    fn build_request_body(&self, contents: Vec<Content>) -> GeminiRequestBody {
-        GeminiRequestBody {
+    pub(crate) async fn build_request_body(&self, contents: Vec<Content>) -> Result<GeminiRequestBody> {
+        self.options.validate()?;
+        #[cfg(feature = "image_analysis")]
+        let contents = self.resolve_image_urls(contents).await?;
+        #[cfg(feature = "image_analysis")]
+        check_inline_data_size(&contents)?;
+        #[cfg(not(feature = "image_analysis"))]
+        if contents
+            .iter()
+            .flat_map(|content| &content.parts)
+            .any(|part| matches!(part, Part::ImageUrl(_)))
+        {
+            bail!("Images referenced by URL require the image_analysis feature")
+        }
+        let cached_content = match &self.cached_content {
+            Some(cache) if cache.model != self.model.resource_name() => {
+                bail!(GeminiError::CachedContentModelMismatch {
+                    cached_model: cache.model.clone(),
+                    model: self.model.resource_name(),
+                })
+            }
+            Some(cache) => Some(cache.name.clone()),
+            None => None,
+        };
+        Ok(GeminiRequestBody {
             contents,
             generation_config: Some(self.options.clone()),
-            system_instruction: self.system_instruction.as_ref().map(|s| Content {
-                parts: vec![Part::Text(s.clone())],
-                role: None,
-            }),
+            // 系统指令已包含在缓存内容中时不再重复发送
+            system_instruction: match cached_content {
+                Some(_) if self.system_instruction_cached => None,
+                _ => self.effective_system_instruction().map(|s| Content {
+                    parts: vec![Part::Text(s)],
+                    role: None,
+                }),
+            },
+            cached_content,
+            safety_settings: self.safety_settings.clone(),
             ..Default::default()
+        })
+    }
+
+    /// 读取本地图片或下载网络图片，返回图片类型以及对应的 base64 编码字符串，下载失败时按配置重试
+    #[cfg(feature = "image_analysis")]
+    async fn load_image(&self, image_path: String) -> Result<(String, String)> {
+        use crate::utils::image::{download_image_with_retries, get_image_type_and_base64_string};
+
+        if image_path.starts_with("https://") || image_path.starts_with("http://") {
+            download_image_with_retries(&self.client, &image_path, self.image_download_retries).await
+        } else {
+            get_image_type_and_base64_string(image_path).await
         }
     }
 
-    /// 异步单次对话
-    #[deprecated(since = "0.5.0", note = "Please use `sendMessage` instead.")]
-    pub async fn chat_once(&self, content: String) -> Result<String> {
-        // 创建一个客户端实例
-        let url = format!("{}?key={}", self.url, self.key);
-        let contents = vec![Content {
-            role: Some(Role::User),
-            parts: vec![Part::Text(content)],
-        }];
-        let body = self.build_request_body(contents);
-        let body_json = serde_json::to_string(&body)?;
-        // 发送 GET 请求，并添加自定义头部
-        let response = self
-            .client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(body_json)
-            .send()
-            .await?;
+    /// 下载以 URL 引用的图片，替换为内联数据
+    #[cfg(feature = "image_analysis")]
+    async fn resolve_image_urls(&self, mut contents: Vec<Content>) -> Result<Vec<Content>> {
+        use crate::utils::image::download_image_with_retries;
+
+        for part in contents.iter_mut().flat_map(|content| &mut content.parts) {
+            if let Part::ImageUrl(url) = part {
+                let (mime_type, data) =
+                    download_image_with_retries(&self.client, url, self.image_download_retries).await?;
+                *part = Part::InlineData {
+                    mime_type,
+                    data,
+                    video_metadata: None,
+                };
+            }
+        }
+        Ok(contents)
+    }
+
+    /// 发送 POST 请求
+    /// 配置了多个 API key 时轮流使用，某个 key 触发 429 限流后进入冷却并换用下一个 key 重试
+    async fn post(&self, url: &str, body_json: String) -> Result<HttpResponse<Response>> {
+        let attempts = self.keys.len().max(1);
+        let mut attempt = 1;
+        loop {
+            let key = self.keys.next().unwrap_or_else(|| self.key.clone());
+            let (url, mut headers) = authorize(url, &key, &self.headers, self.vertex.is_some())?;
+            let response = match &self.transport {
+                Some(transport) => {
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                    let (status, text) = transport.post(&url, &headers, body_json.clone()).await?;
+                    HttpResponse::Transport {
+                        status: StatusCode::from_u16(status)?,
+                        body: text.into_bytes(),
+                    }
+                }
+                None => self
+                    .client
+                    .post(url)
+                    .headers(headers)
+                    .header("Content-Type", "application/json")
+                    .body(body_json.clone())
+                    .send()
+                    .await
+                    .map_err(redact_error)?
+                    .into(),
+            };
+            if response.status() == StatusCode::TOO_MANY_REQUESTS && self.keys.len() > 0 {
+                self.keys.cool_down(&key);
+                if attempt < attempts {
+                    attempt += 1;
+                    continue;
+                }
+            }
+            return Ok(response);
+        }
+    }
+
+    /// 读取响应体文本，配置了响应大小上限时超过上限返回 `GeminiError::ResponseTooLarge`
+    async fn read_text(&self, response: impl Into<HttpResponse<Response>>) -> Result<String> {
+        let mut response = response.into();
+        let Some(limit) = self.max_response_bytes else {
+            return Ok(response.text().await.map_err(redact_error)?);
+        };
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(redact_error)? {
+            body.extend_from_slice(&chunk);
+            if body.len() > limit {
+                bail!(GeminiError::ResponseTooLarge(limit))
+            }
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// 发送请求并解析响应内容
+    async fn generate(&self, body: &GeminiRequestBody) -> Result<GenerateContentResponse> {
+        let body_json = serde_json::to_string(body)?;
+        let response = self.post(&self.method_url("generateContent"), body_json).await?;
         if response.status().is_success() {
-            let response_text = response.text().await?;
+            let response_text = self.read_text(response).await?;
             // 解析响应内容
-            let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-            match response.candidates[0].content.parts[0].clone() {
-                Part::Text(s) => Ok(s),
-                _ => bail!("Unexpected response format"),
-            }
+            self.parse_generated(&response_text)
         } else {
-            let response_text = response.text().await?;
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
             // 解析错误响应内容
-            let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-            let error_message = response_error.error.message;
-            bail!(error_message)
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
+    /// 回复为空时重新发送请求，直到回复不为空或达到 `set_retry_on_empty` 配置的次数
+    async fn resend_if_empty(
+        &self,
+        body: &GeminiRequestBody,
+        mut response: GenerateContentResponse,
+    ) -> Result<GenerateContentResponse> {
+        let Some(max_retries) = self.retry_on_empty else {
+            return Ok(response);
+        };
+        let mut retries = 0;
+        while is_empty_response(&response) {
+            if retries == max_retries {
+                bail!(GeminiError::EmptyResponse(max_retries))
+            }
+            retries += 1;
+            response = self.generate(body).await?;
+        }
+        Ok(response)
+    }
+
+    /// 解析响应内容，并记录请求使用的模型
+    fn parse_generated(&self, response_text: &str) -> Result<GenerateContentResponse> {
+        let mut response = parse_response(response_text, self.lenient_parsing)?;
+        response.requested_model = Some(self.model.resource_name());
+        Ok(response)
+    }
+
+    /// 请求失败时移除最后发送的那次用户请求，配置了保留失败请求时不移除
+    fn drop_failed_turn(&mut self) {
+        if !self.keep_failed_turns {
+            self.contents.pop();
+        }
+    }
+
+    /// 将模型回复的全部内容加入历史记录，并返回回复文本
+    /// 保存全部内容而非仅文本，使函数调用、代码执行等非文本内容也能原样发回
+    fn record_response(&mut self, response: &GenerateContentResponse) -> String {
+        self.record_response_with_prefix(response, "")
+    }
+
+    /// 与 `record_response` 相同，但在回复的第一个文本部分前加上预先给定的回复开头 prefix
+    fn record_response_with_prefix(&mut self, response: &GenerateContentResponse, prefix: &str) -> String {
+        self.last_finish_reason = response.candidates.first().and_then(|c| c.finish_reason.clone());
+        self.last_candidates = Some(response.candidates.clone());
+        let Some(mut content) = response.to_model_content() else {
+            return String::new();
+        };
+        if self.strip_role_prefix {
+            if let Some(Part::Text(text)) = content.parts.first_mut() {
+                *text = strip_role_prefix(text).to_owned();
+            }
+        }
+        if !prefix.is_empty() {
+            match content.parts.iter_mut().find(|part| matches!(part, Part::Text(_))) {
+                Some(Part::Text(text)) => text.insert_str(0, prefix),
+                _ => content.parts.insert(0, Part::Text(prefix.to_owned())),
+            }
+        }
+        let text = self
+            .extract_strategy
+            .extract_joined(&content, &self.text_join_separator);
+        self.push_model_turn(content);
+        text
+    }
+
+    /// 开启历史记录时将模型回复加入历史记录，并去掉其中连续重复的部分
+    /// 不与上一轮回复比较，相同的回复在不同轮次中出现是正常的
+    fn push_model_turn(&mut self, mut content: Content) {
+        if self.conversation {
+            content.dedup_parts();
+            self.contents.push(content);
+        }
+    }
+
+    /// 发送单次文本请求并返回第一个候选回复的文本
+    async fn generate_text(&self, prompt: String) -> Result<String> {
+        let body = self.build_request_body(vec![(Role::User, prompt).into()]).await?;
+        let response = self.generate(&body).await?;
+        match response.candidates.first() {
+            Some(candidate) => Ok(candidate.content.text_joined(&self.text_join_separator)),
+            None => bail!(GeminiError::NoTextPart),
+        }
+    }
+
+    /// 异步单次对话
+    #[deprecated(since = "0.5.0", note = "Please use `sendMessage` instead.")]
+    pub async fn chat_once(&self, content: String) -> Result<String> {
+        // 创建一个客户端实例
+        let contents = vec![Content {
+            role: Some(Role::User),
+            parts: vec![Part::Text(content)],
+        }];
+        let body = self.build_request_body(contents).await?;
+        let body_json = serde_json::to_string(&body)?;
+        // 发送 GET 请求，并添加自定义头部
+        let response = self.post(&self.method_url("generateContent"), body_json).await?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            let response = self.parse_generated(&response_text)?;
+            match first_part(&response)? {
+                Part::Text(s) => Ok(s),
+                _ => bail!("Unexpected response format"),
+            }
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
         }
     }
 
@@ -131,22 +1137,15 @@ impl Gemini {
             parts: vec![Part::Text(content)],
         });
         let cloned_contents = self.contents.clone();
-        let url = format!("{}?key={}", self.url, self.key);
-        let body = self.build_request_body(cloned_contents);
+        let body = self.build_request_body(cloned_contents).await?;
         let body_json = serde_json::to_string(&body)?;
         // 发送 GET 请求，并添加自定义头部
-        let response = self
-            .client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(body_json)
-            .send()
-            .await?;
+        let response = self.post(&self.method_url("generateContent"), body_json).await?;
         if response.status().is_success() {
-            let response_text = response.text().await?;
+            let response_text = self.read_text(response).await?;
             // 解析响应内容
-            let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-            match response.candidates[0].content.parts[0].clone().clone() {
+            let response = self.parse_generated(&response_text)?;
+            match first_part(&response)? {
                 Part::Text(s) => {
                     self.contents.push(Content {
                         role: Some(Role::Model),
@@ -158,12 +1157,11 @@ impl Gemini {
             }
         } else {
             // 如果响应失败，则移除最后发送的那次用户请求
-            self.contents.pop();
-            let response_text = response.text().await?;
+            self.drop_failed_turn();
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
             // 解析错误响应内容
-            let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-            let error_message = response_error.error.message;
-            bail!(error_message)
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
         }
     }
 
@@ -174,7 +1172,6 @@ impl Gemini {
         use crate::utils::image::get_image_type_and_base64_string;
 
         let (image_type, base64_string) = get_image_type_and_base64_string(image_path).await?;
-        let url = format!("{}?key={}", self.url, self.key);
 
         // 请求内容
         let contents = vec![Content {
@@ -184,34 +1181,28 @@ impl Gemini {
                 Part::InlineData {
                     mime_type: image_type,
                     data: base64_string,
+                    video_metadata: None,
                 },
             ],
         }];
-        let body = self.build_request_body(contents);
+        let body = self.build_request_body(contents).await?;
         let body_json = serde_json::to_string(&body)?;
 
         // 发送 GET 请求，并添加自定义头部
-        let response = self
-            .client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(body_json)
-            .send()
-            .await?;
+        let response = self.post(&self.method_url("generateContent"), body_json).await?;
         if response.status().is_success() {
-            let response_text = response.text().await?;
+            let response_text = self.read_text(response).await?;
             // 解析响应内容
-            let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-            match response.candidates[0].content.parts[0].clone() {
+            let response = self.parse_generated(&response_text)?;
+            match first_part(&response)? {
                 Part::Text(s) => Ok(s),
                 _ => bail!("Unexpected response format"),
             }
         } else {
-            let response_text = response.text().await?;
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
             // 解析响应内容
-            let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-            let error_message = response_error.error.message;
-            bail!(error_message)
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
         }
     }
 
@@ -223,29 +1214,9 @@ impl Gemini {
         note = "Please use `start_chat` & `sendMessage` instead, which supports continuous conversation."
     )]
     pub async fn image_analysis_conversation(&mut self, image_path: String, text: String) -> Result<String> {
-        use base64::{engine::general_purpose, Engine as _};
-        use image::EncodableLayout;
-        use std::{fs::File, io::Read};
-
-        use crate::utils::image::guess_image_format;
-
-        let (image_type, base64_string) = if image_path.starts_with("https://") || image_path.starts_with("http://") {
-            let response = self.client.get(image_path).send().await?;
-            if response.status().is_success() {
-                let bytes = response.bytes().await?; // 读取整个响应体为字节
-                let base64_string = general_purpose::STANDARD.encode(&bytes);
-                (guess_image_format(bytes.as_bytes()), base64_string)
-            } else {
-                bail!("Failed to download image, status: {}", response.status());
-            }
-        } else {
-            let mut buffer = Vec::new();
-            let mut file = File::open(image_path)?;
-            file.read_to_end(&mut buffer)?;
-            let base64_string = general_purpose::STANDARD.encode(&buffer);
-            (guess_image_format(buffer.as_slice()), base64_string)
-        };
-        let url = format!("{}?key={}", self.url, self.key);
+        use crate::utils::image::get_image_type_and_base64_string;
+
+        let (image_type, base64_string) = get_image_type_and_base64_string(image_path).await?;
 
         // 请求内容
         self.contents.push(Content {
@@ -255,26 +1226,21 @@ impl Gemini {
                 Part::InlineData {
                     mime_type: image_type,
                     data: base64_string,
+                    video_metadata: None,
                 },
             ],
         });
         let cloned_contents = self.contents.clone();
-        let body = self.build_request_body(cloned_contents);
+        let body = self.build_request_body(cloned_contents).await?;
         let body_json = serde_json::to_string(&body)?;
 
         // 发送 GET 请求，并添加自定义头部
-        let response = self
-            .client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .body(body_json)
-            .send()
-            .await?;
+        let response = self.post(&self.method_url("generateContent"), body_json).await?;
         if response.status().is_success() {
-            let response_text = response.text().await?;
+            let response_text = self.read_text(response).await?;
             // 解析响应内容
-            let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-            match response.candidates[0].content.parts[0].clone().clone() {
+            let response = self.parse_generated(&response_text)?;
+            match first_part(&response)? {
                 Part::Text(s) => {
                     self.contents.push(Content {
                         role: Some(Role::Model),
@@ -285,12 +1251,11 @@ impl Gemini {
                 _ => bail!("Unexpected response format"),
             }
         } else {
-            self.contents.pop();
-            let response_text = response.text().await?;
+            self.drop_failed_turn();
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
             // 解析响应内容
-            let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-            let error_message = response_error.error.message;
-            bail!(error_message)
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
         }
     }
 
@@ -302,164 +1267,117 @@ impl Gemini {
 
     /// 发送消息
     pub async fn send_message(&mut self, message: Content) -> Result<(String, GenerateContentResponse)> {
-        if !self.conversation {
-            // 创建一个客户端实例
-            let url = format!("{}?key={}", self.url, self.key);
-            let contents = vec![message];
-            let body = self.build_request_body(contents);
-            let body_json = serde_json::to_string(&body)?;
-            // 发送 GET 请求，并添加自定义头部
-            let response = self
-                .client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(body_json)
-                .send()
-                .await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-                match response.candidates[0].content.parts[0].clone().clone() {
-                    Part::Text(s) => {
-                        self.contents.push(Content {
-                            role: Some(Role::Model),
-                            parts: vec![Part::Text(s.clone())],
-                        });
-                        Ok((s, response))
-                    }
-                    _ => bail!("Unexpected response format"),
-                }
-            } else {
-                let response_text = response.text().await?;
-                // 解析错误响应内容
-                let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-                let error_message = response_error.error.message;
-                bail!(error_message)
-            }
+        self.send_turn(message).await
+    }
+
+    /// 发送单次文本请求，只返回回复文本
+    /// 不读取也不记录历史记录，是发送提示词获取文本的最简方式
+    pub async fn ask(&self, prompt: &str) -> Result<String> {
+        self.generate_text(prompt.to_owned()).await
+    }
+
+    /// 批量发送函数调用结果，responses 为函数名称与对应的返回值
+    /// 所有结果合并为同一条函数角色的消息发送，用于回复模型一次请求的多个并行函数调用
+    pub async fn send_function_responses(
+        &mut self,
+        responses: Vec<(String, serde_json::Value)>,
+    ) -> Result<(String, GenerateContentResponse)> {
+        let parts = responses
+            .into_iter()
+            .map(|(name, response)| Part::FunctionResponse(FunctionResponse::new(name, response)))
+            .collect();
+        self.send_message(Content {
+            parts,
+            role: Some(Role::Function),
+        })
+        .await
+    }
+
+    /// 发送简单文本消息
+    pub async fn send_simple_message(&mut self, message: String) -> Result<(String, GenerateContentResponse)> {
+        self.send_message((Role::User, message).into()).await
+    }
+
+    /// 发送文本消息，返回回复文本以及原始的响应 JSON 字符串，便于调试与存档
+    pub async fn send_simple_message_raw(&mut self, message: String) -> Result<(String, String)> {
+        let contents = if self.conversation {
+            self.contents.push((Role::User, message).into());
+            self.contents.clone()
         } else {
-            self.contents.push(message);
-            let cloned_contents = self.contents.clone();
-            let url = format!("{}?key={}", self.url, self.key);
-            let body = self.build_request_body(cloned_contents);
-            let body_json = serde_json::to_string(&body)?;
-            // 发送 GET 请求，并添加自定义头部
-            let response = self
-                .client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(body_json)
-                .send()
-                .await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-                match response.candidates[0].content.parts[0].clone().clone() {
-                    Part::Text(s) => {
-                        self.contents.push(Content {
-                            role: Some(Role::Model),
-                            parts: vec![Part::Text(s.clone())],
-                        });
-                        Ok((s, response))
-                    }
-                    _ => bail!("Unexpected response format"),
+            vec![(Role::User, message).into()]
+        };
+        match self.generate_raw(contents).await {
+            Ok((response, raw)) => Ok((self.record_response(&response), raw)),
+            Err(e) => {
+                if self.conversation {
+                    self.drop_failed_turn();
                 }
-            } else {
-                // 如果响应失败，则移除最后发送的那次用户请求
-                self.contents.pop();
-                let response_text = response.text().await?;
-                // 解析错误响应内容
-                let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-                let error_message = response_error.error.message;
-                bail!(error_message)
+                Err(e)
             }
         }
     }
 
-    /// 发送简单文本消息
-    pub async fn send_simple_message(&mut self, message: String) -> Result<(String, GenerateContentResponse)> {
+    /// 发送消息并记录回复，开启历史记录时先将消息加入历史记录
+    /// 之后任何一步失败都会移除该消息（配置了保留失败请求时除外），避免历史记录中出现连续的用户请求
+    async fn send_turn(&mut self, message: Content) -> Result<(String, GenerateContentResponse)> {
         if !self.conversation {
-            // 创建一个客户端实例
-            let url = format!("{}?key={}", self.url, self.key);
-            let contents = vec![Content {
-                parts: vec![Part::Text(message.clone())],
-                role: Some(Role::User),
-            }];
-            let body = self.build_request_body(contents);
-            let body_json = serde_json::to_string(&body)?;
-            // 发送 GET 请求，并添加自定义头部
-            let response = self
-                .client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(body_json)
-                .send()
-                .await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-                match response.candidates[0].content.parts[0].clone().clone() {
-                    Part::Text(s) => {
-                        self.contents.push(Content {
-                            role: Some(Role::Model),
-                            parts: vec![Part::Text(s.clone())],
-                        });
-                        Ok((s, response))
-                    }
-                    _ => bail!("Unexpected response format"),
-                }
-            } else {
-                let response_text = response.text().await?;
-                // 解析错误响应内容
-                let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-                let error_message = response_error.error.message;
-                bail!(error_message)
+            let response = self.generate_reply(vec![message]).await?;
+            let text = self.record_response(&response);
+            return Ok((text, response));
+        }
+        self.contents.push(message);
+        match self.generate_history_reply().await {
+            Ok(response) => {
+                let text = self.record_response(&response);
+                Ok((text, response))
             }
-        } else {
-            self.contents.push(Content {
-                parts: vec![Part::Text(message.clone())],
-                role: Some(Role::User),
-            });
-            let cloned_contents = self.contents.clone();
-            let url = format!("{}?key={}", self.url, self.key);
-            let body = self.build_request_body(cloned_contents);
-            let body_json = serde_json::to_string(&body)?;
-            // 发送 GET 请求，并添加自定义头部
-            let response = self
-                .client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(body_json)
-                .send()
-                .await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-                match response.candidates[0].content.parts[0].clone().clone() {
-                    Part::Text(s) => {
-                        self.contents.push(Content {
-                            role: Some(Role::Model),
-                            parts: vec![Part::Text(s.clone())],
-                        });
-                        Ok((s, response))
-                    }
-                    _ => bail!("Unexpected response format"),
-                }
-            } else {
-                // 如果响应失败，则移除最后发送的那次用户请求
-                self.contents.pop();
-                let response_text = response.text().await?;
-                // 解析错误响应内容
-                let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-                let error_message = response_error.error.message;
-                bail!(error_message)
+            Err(e) => {
+                self.drop_failed_turn();
+                Err(e)
             }
         }
     }
 
+    /// 以完整的历史记录发送请求，历史记录中以 URL 引用的图片下载后直接替换为内联数据，之后的请求不再重复下载
+    /// 配置了自动裁剪时，在图片下载之后、发送之前裁剪历史记录
+    async fn generate_history_reply(&mut self) -> Result<GenerateContentResponse> {
+        #[cfg(feature = "image_analysis")]
+        if self
+            .contents
+            .iter()
+            .flat_map(|content| &content.parts)
+            .any(|part| matches!(part, Part::ImageUrl(_)))
+        {
+            self.contents = self.resolve_image_urls(self.contents.clone()).await?;
+        }
+        self.trim_history().await?;
+        self.generate_reply(self.contents.clone()).await
+    }
+
+    /// 发送请求并解析响应内容，回复为空时按配置重新发送
+    async fn generate_reply(&self, contents: Vec<Content>) -> Result<GenerateContentResponse> {
+        let body = self.build_request_body(contents).await?;
+        let response = self.generate(&body).await?;
+        self.resend_if_empty(&body, response).await
+    }
+
+    /// 发送请求并返回解析后的响应以及原始的响应体
+    async fn generate_raw(&self, contents: Vec<Content>) -> Result<(GenerateContentResponse, String)> {
+        let body = self.build_request_body(contents).await?;
+        let body_json = serde_json::to_string(&body)?;
+        let response = self.post(&self.method_url("generateContent"), body_json).await?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            Ok((self.parse_generated(&response_text)?, response_text))
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
     /// 发送图片文本消息
     #[cfg(feature = "image_analysis")]
     pub async fn send_image_message(
@@ -467,119 +1385,1280 @@ impl Gemini {
         image_path: String,
         text: String,
     ) -> Result<(String, GenerateContentResponse)> {
-        use base64::{engine::general_purpose, Engine as _};
-        use image::EncodableLayout;
-        use std::{fs::File, io::Read};
+        let (image_type, base64_string) = self.load_image(image_path).await?;
 
-        use crate::utils::image::get_image_type_and_base64_string;
-        use crate::utils::image::guess_image_format;
-        if !self.conversation {
-            let (image_type, base64_string) = get_image_type_and_base64_string(image_path).await?;
-            let url = format!("{}?key={}", self.url, self.key);
-
-            // 请求内容
-            let contents = vec![Content {
-                role: Some(Role::User),
-                parts: vec![
-                    Part::Text(text),
-                    Part::InlineData {
-                        mime_type: image_type,
-                        data: base64_string,
-                    },
-                ],
-            }];
-            let body = self.build_request_body(contents);
-            let body_json = serde_json::to_string(&body)?;
+        // 请求内容
+        // 先文本后图片
+        let message = Content {
+            role: Some(Role::User),
+            parts: vec![
+                Part::Text(text),
+                Part::InlineData {
+                    mime_type: image_type,
+                    data: base64_string,
+                    video_metadata: None,
+                },
+            ],
+        };
+        self.send_turn(message).await
+    }
+
+    /// 以预先给定的模型回复开头发送消息，模型将从 model_prefix 处继续生成，可用于引导回复格式
+    /// 续写内容的第一个文本部分前会加上 model_prefix，之后与其他发送方法一样记录完整的回复内容并按提取方式返回文本
+    /// 注意并非所有模型都支持以模型回复结尾的请求，不支持时 API 会返回错误
+    pub async fn send_with_model_prefix(
+        &mut self,
+        user: String,
+        model_prefix: String,
+    ) -> Result<(String, GenerateContentResponse)> {
+        let mut contents = if self.conversation {
+            self.contents.clone()
+        } else {
+            Vec::new()
+        };
+        contents.push((Role::User, user.clone()).into());
+        contents.push((Role::Model, model_prefix.clone()).into());
+        let body = self.build_request_body(contents).await?;
+        let response = self.generate(&body).await?;
+        if response.candidates.is_empty() {
+            bail!(GeminiError::NoTextPart)
+        }
+        if self.conversation {
+            self.contents.push((Role::User, user).into());
+        }
+        let text = self.record_response_with_prefix(&response, &model_prefix);
+        Ok((text, response))
+    }
+
+    /// 使用当前历史记录重新请求模型回复
+    /// 仅在开启历史记录且最后一条记录为用户请求时可用，通常配合 `set_keep_failed_turns` 重试失败的请求
+    /// 与发送消息一样按配置重试空回复、裁剪历史记录，历史记录中以 URL 引用的图片只下载一次
+    pub async fn regenerate(&mut self) -> Result<(String, GenerateContentResponse)> {
+        let pending = self.conversation
+            && self
+                .contents
+                .last()
+                .is_some_and(|content| content.role == Some(Role::User));
+        if !pending {
+            bail!("The last message of the conversation is not a user message")
+        }
+        match self.generate_history_reply().await {
+            Ok(response) => {
+                let text = self.record_response(&response);
+                Ok((text, response))
+            }
+            Err(e) => {
+                self.drop_failed_turn();
+                Err(e)
+            }
+        }
+    }
+
+    /// 继续生成因达到最大输出 token 数而被截断的回复
+    /// 仅在开启历史记录且上一次回复因 `FinishReason::MaxTokens` 结束时可用
+    /// 续写内容会追加到历史记录中的上一条模型回复，返回拼接后的完整回复文本
+    pub async fn continue_generation(&mut self) -> Result<(String, GenerateContentResponse)> {
+        let truncated = self.conversation
+            && matches!(self.last_finish_reason, Some(FinishReason::MaxTokens))
+            && self
+                .contents
+                .last()
+                .is_some_and(|content| content.role == Some(Role::Model));
+        if !truncated {
+            bail!("The last response was not cut off by the max output tokens")
+        }
+        let last = self.contents.len() - 1;
+        let mut contents = self.contents.clone();
+        contents.push((Role::User, RESUME_PROMPT.to_owned()).into());
+        let body = self.build_request_body(contents).await?;
+        let response = self.generate(&body).await?;
+        let Some(candidate) = response.candidates.first() else {
+            bail!(GeminiError::NoTextPart)
+        };
+        self.last_finish_reason = candidate.finish_reason.clone();
+        self.last_candidates = Some(response.candidates.clone());
+        self.contents[last]
+            .parts
+            .extend(candidate.content.parts.iter().cloned());
+        let text = self.contents[last].text_joined(&self.text_join_separator);
+        Ok((text, response))
+    }
+
+    /// 发送视频文本消息
+    /// 视频需先通过 Files API 上传，传入上传后返回的文件 URI 以及视频的 MIME 类型
+    /// 可通过 video_metadata 指定分析的时间区间以及帧率
+    pub async fn send_video_message(
+        &mut self,
+        file_uri: String,
+        mime_type: String,
+        text: String,
+        video_metadata: Option<VideoMetadata>,
+    ) -> Result<(String, GenerateContentResponse)> {
+        check_file_uri(&file_uri)?;
+        // 先文本后视频
+        let message = Content {
+            role: Some(Role::User),
+            parts: vec![
+                Part::Text(text),
+                Part::FileData {
+                    mime_type: Some(mime_type),
+                    file_uri,
+                    video_metadata,
+                },
+            ],
+        };
+        self.send_message(message).await
+    }
 
-            // 发送 GET 请求，并添加自定义头部
+    /// 将系统指令（包括回复语言要求）缓存到与当前模型绑定的缓存内容中，缓存在 `SYSTEM_INSTRUCTION_CACHE_TTL` 之后过期
+    /// 之后的请求引用该缓存而不再每次发送系统指令，修改系统指令后需重新调用，没有系统指令时返回错误
+    pub async fn cache_system_instruction(&mut self) -> Result<()> {
+        let Some(system_instruction) = self.effective_system_instruction() else {
+            bail!("No system instruction to cache")
+        };
+        self.create_cached_content(
+            self.model.clone(),
+            None,
+            Vec::new(),
+            Some(system_instruction),
+            SYSTEM_INSTRUCTION_CACHE_TTL,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// 创建与指定模型绑定的缓存内容，缓存在 ttl 之后过期
+    /// 创建成功后客户端的后续请求都会使用该缓存，此时请求的模型需与缓存绑定的模型一致
+    /// 缓存内容包含系统指令时，之后的请求不再发送系统指令
+    pub async fn create_cached_content(
+        &mut self,
+        model: LanguageModel,
+        display_name: Option<String>,
+        contents: Vec<Content>,
+        system_instruction: Option<String>,
+        ttl: Duration,
+    ) -> Result<CachedContent> {
+        let system_instruction_cached = system_instruction.is_some();
+        let body = CreateCachedContentRequest {
+            model: model.resource_name(),
+            display_name,
+            contents,
+            system_instruction: system_instruction.map(|s| Content {
+                parts: vec![Part::Text(s)],
+                role: None,
+            }),
+            ttl: format!("{}s", ttl.as_secs_f64()),
+        };
+        let body_json = serde_json::to_string(&body)?;
+        let response = self
+            .post(&format!("{}cachedContents", self.api_url()), body_json)
+            .await?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            let cached_content: CachedContent = serde_json::from_str(&response_text)?;
+            self.cached_content = Some(cached_content.clone());
+            self.system_instruction_cached = system_instruction_cached;
+            Ok(cached_content)
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
+    /// 通过 Files API 上传文件，返回上传后的文件信息
+    /// 文件按块以断点续传协议上传，每上传完一块调用一次 on_progress(已上传字节数, 总字节数)
+    /// mime_type 为空时根据文件内容与扩展名自动识别，见 `utils::mime::guess_mime`
+    /// 返回的 `File::uri` 可用于 `send_video_message`
+    /// Vertex AI 不支持 Files API，使用 `Gemini::vertex` 创建的客户端调用时返回错误，不会发送访问令牌
+    pub async fn upload_file(
+        &self,
+        path: String,
+        mime_type: String,
+        mut on_progress: impl FnMut(u64, u64),
+    ) -> Result<File> {
+        if self.vertex.is_some() {
+            bail!("The Files API is not available on Vertex AI, reference files in Cloud Storage instead")
+        }
+        let mime_type = if mime_type.is_empty() {
+            let mut head = Vec::new();
+            tokio::fs::File::open(&path)
+                .await?
+                .take(MIME_SNIFF_LEN)
+                .read_to_end(&mut head)
+                .await?;
+            detect_mime_type(&path, &head)?
+        } else {
+            mime_type
+        };
+        let mut file = tokio::fs::File::open(&path).await?;
+        let total = file.metadata().await?.len();
+        let display_name = Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let key = self.keys.next().unwrap_or_else(|| self.key.clone());
+        // 开始上传，获取本次上传的地址
+        let response = self
+            .client
+            .post(format!("{}?key={}", GEMINI_UPLOAD_URL, key))
+            .headers(self.headers.clone())
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", total)
+            .header("X-Goog-Upload-Header-Content-Type", &mime_type)
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "file": { "display_name": display_name } }).to_string())
+            .send()
+            .await
+            .map_err(redact_error)?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+        let Some(upload_url) = response
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|url| url.to_str().ok())
+            .map(str::to_owned)
+        else {
+            bail!("Missing upload url in response")
+        };
+        // 分块上传，最后一块同时结束上传
+        let mut offset = 0;
+        loop {
+            let size = (total - offset).min(UPLOAD_CHUNK_SIZE);
+            let mut chunk = vec![0; size as usize];
+            file.read_exact(&mut chunk).await?;
+            let command = if offset + size == total {
+                "upload, finalize"
+            } else {
+                "upload"
+            };
             let response = self
                 .client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(body_json)
+                .post(&upload_url)
+                .headers(self.headers.clone())
+                .header("X-Goog-Upload-Command", command)
+                .header("X-Goog-Upload-Offset", offset)
+                .body(chunk)
                 .send()
-                .await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-                match response.candidates[0].content.parts[0].clone() {
-                    Part::Text(s) => Ok((s, response)),
-                    _ => bail!("Unexpected response format"),
-                }
-            } else {
-                let response_text = response.text().await?;
+                .await
+                .map_err(redact_error)?;
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            if !status.is_success() {
+                // 解析错误响应内容
+                bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+            }
+            offset += size;
+            on_progress(offset, total);
+            if offset == total {
                 // 解析响应内容
-                let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-                let error_message = response_error.error.message;
-                bail!(error_message)
+                let response: UploadFileResponse = serde_json::from_str(&response_text)?;
+                return Ok(response.file);
             }
+        }
+    }
+
+    /// 计算发送消息将消耗的输入 token 数
+    /// 开启历史记录时包含历史消息，同时计入系统指令
+    pub async fn count_tokens(&self, message: Content) -> Result<isize> {
+        let mut contents = if self.conversation {
+            self.contents.clone()
         } else {
-            let (image_type, base64_string) = if image_path.starts_with("https://") || image_path.starts_with("http://")
-            {
-                let response = self.client.get(image_path).send().await?;
-                if response.status().is_success() {
-                    let bytes = response.bytes().await?; // 读取整个响应体为字节
-                    let base64_string = general_purpose::STANDARD.encode(&bytes);
-                    (guess_image_format(bytes.as_bytes()), base64_string)
-                } else {
-                    bail!("Failed to download image, status: {}", response.status());
-                }
-            } else {
-                let mut buffer = Vec::new();
-                let mut file = File::open(image_path)?;
-                file.read_to_end(&mut buffer)?;
-                let base64_string = general_purpose::STANDARD.encode(&buffer);
-                (guess_image_format(buffer.as_slice()), base64_string)
+            Vec::new()
+        };
+        contents.push(message);
+        self.count_contents_tokens(contents).await
+    }
+
+    /// 计算当前历史记录的输入 token 数，同时计入系统指令，可用于展示当前上下文大小
+    pub async fn current_token_count(&self) -> Result<isize> {
+        self.count_contents_tokens(self.contents.clone()).await
+    }
+
+    /// 获取实际生效的最大输出 token 数，即配置的 maxOutputTokens 与模型 output_token_limit 中较小的一个
+    /// 未配置 maxOutputTokens 时返回模型的 output_token_limit
+    pub async fn effective_max_output(&self) -> Result<isize> {
+        let model = self.get_model().await?;
+        Ok(effective_output_limit(
+            self.options.max_output_tokens,
+            model.output_token_limit,
+        ))
+    }
+
+    /// 判断内容是否在当前模型的输入 token 上限之内
+    /// 通过 countTokens 计算 token 数（包括图片等多模态内容以及系统指令），并与模型的 input_token_limit 比较
+    pub async fn fits(&self, contents: Vec<Content>) -> Result<bool> {
+        let tokens = self.count_contents_tokens(contents).await?;
+        let model = self.get_model().await?;
+        Ok(tokens <= model.input_token_limit)
+    }
+
+    /// 开启历史记录自动裁剪，发送消息前历史记录与新消息的输入 token 数超过 max_tokens 时，从最早的一轮对话开始移除
+    /// 适用于开启历史记录时的 `send_message`、`send_simple_message`、`send_image_message` 等发送方法
+    /// 每次发送前会额外请求一次 countTokens 计算整个历史记录的 token 数，超过上限时再为每一轮被移除的对话请求一次 countTokens，
+    /// 这些请求只包含该轮对话的内容
+    pub fn set_auto_trim_tokens(&mut self, max_tokens: isize) {
+        self.auto_trim_tokens = Some(max_tokens);
+    }
+
+    /// 以模型的 input_token_limit 作为历史记录自动裁剪的上限，并为回复预留 headroom_fraction 比例的 token
+    /// 模型信息只在调用时获取一次，headroom_fraction 需在 [0, 1) 之间，如 0.2 表示使用 80% 的输入 token 上限
+    pub async fn set_auto_trim_to_model_limit(&mut self, headroom_fraction: f64) -> Result<()> {
+        if !(0.0..1.0).contains(&headroom_fraction) {
+            bail!("Headroom fraction must be within [0, 1), got {}", headroom_fraction)
+        }
+        let model = self.get_model().await?;
+        self.set_auto_trim_tokens((model.input_token_limit as f64 * (1.0 - headroom_fraction)) as isize);
+        Ok(())
+    }
+
+    /// 按自动裁剪的上限移除最早的对话，直到历史记录不超过上限，历史记录的最后一条为即将发送的消息
+    /// 整个历史记录只计算一次 token 数，之后按被移除的每一轮对话各自的 token 数扣减，不会重复发送整个历史记录
+    /// 每次移除一整轮对话，使历史记录仍以用户消息开头，至少保留即将发送的消息
+    async fn trim_history(&mut self) -> Result<()> {
+        let Some(max_tokens) = self.auto_trim_tokens else {
+            return Ok(());
+        };
+        let mut excess = self.count_contents_tokens(self.contents.clone()).await? - max_tokens;
+        while excess > 0 && self.contents.len() > 1 {
+            // 一轮对话从第一条消息开始，直到下一条用户消息之前
+            let end = self.contents[1..]
+                .iter()
+                .position(|content| content.role == Some(Role::User))
+                .map_or(self.contents.len() - 1, |i| i + 1);
+            let body = GeminiRequestBody {
+                contents: self.contents[..end].to_vec(),
+                ..Default::default()
             };
-            let url = format!("{}?key={}", self.url, self.key);
-
-            // 请求内容
-            // 先文本后图片
-            self.contents.push(Content {
-                role: Some(Role::User),
-                parts: vec![
-                    Part::Text(text),
-                    Part::InlineData {
-                        mime_type: image_type,
-                        data: base64_string,
+            excess -= self.count_body_tokens(body).await?;
+            self.contents.drain(..end);
+        }
+        Ok(())
+    }
+
+    /// 快速校验 API key 是否可用，通过一次计算 token 数的请求实现，可在启动时调用以尽早发现配置错误
+    /// API key 无效或无权限（401、403）时返回 `GeminiError::InvalidKey`，其他错误原样返回
+    pub async fn verify_key(&self) -> Result<()> {
+        match self
+            .count_contents_tokens(vec![(Role::User, "ping".to_owned()).into()])
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => match e.downcast_ref::<GeminiError>() {
+                Some(error @ GeminiError::Api { message, .. }) if error.is_key_error() => {
+                    bail!(GeminiError::InvalidKey(message.clone()))
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// 获取当前模型推荐的 temperature、top_p、top_k，并应用到生成配置中，切换模型后调用可使用该模型的默认配置
+    /// 模型未提供的参数保持不变
+    pub async fn use_model_defaults(&mut self) -> Result<()> {
+        let model = self.get_model().await?;
+        apply_model_defaults(&mut self.options, &model);
+        Ok(())
+    }
+
+    /// 获取当前模型的信息，如输入、输出 token 上限
+    pub async fn get_model(&self) -> Result<Model> {
+        let key = self.keys.next().unwrap_or_else(|| self.key.clone());
+        let url = model_url(&self.api_url(), self.vertex.is_some(), &self.model);
+        let (url, headers) = authorize(&url, &key, &self.headers, self.vertex.is_some())?;
+        let response = self
+            .client
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(redact_error)?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            Ok(serde_json::from_str(&response_text)?)
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
+    /// 计算内容的输入 token 数，同时计入系统指令
+    async fn count_contents_tokens(&self, contents: Vec<Content>) -> Result<isize> {
+        let body = self.build_request_body(contents).await?;
+        self.count_body_tokens(body).await
+    }
+
+    /// 计算请求体的输入 token 数
+    async fn count_body_tokens(&self, mut generate_content_request: GeminiRequestBody) -> Result<isize> {
+        let url = self.method_url("countTokens");
+        generate_content_request.model = Some(self.model.resource_name());
+        let body = CountTokensRequest {
+            generate_content_request,
+        };
+        let body_json = serde_json::to_string(&body)?;
+        let response = self.post(&url, body_json).await?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            let response: CountTokensResponse = serde_json::from_str(&response_text)?;
+            Ok(response.total_tokens)
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
+    /// 批量计算文本的向量表示，需使用支持 embedContent 的模型，如 `LanguageModel::Custom("text-embedding-004".into())`
+    /// 文本按每批 `EMBED_BATCH_SIZE` 条分批请求，返回的向量与文本一一对应
+    pub async fn batch_embed_contents(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let (embeddings, _) = self
+            .batch_embed_contents_cancellable(texts, &AtomicBool::new(false))
+            .await?;
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+
+    /// 可取消的批量计算文本的向量表示
+    /// 每发送一批请求前检查 cancelled，为 true 时不再发送剩余批次，已发送的那一批仍会完成
+    /// 返回与文本一一对应的向量表示（未完成的为 None）以及是否被取消
+    pub async fn batch_embed_contents_cancellable(
+        &self,
+        texts: Vec<String>,
+        cancelled: &AtomicBool,
+    ) -> Result<(Vec<Option<Vec<f32>>>, bool)> {
+        let mut embeddings = vec![None; texts.len()];
+        for (i, batch) in texts.chunks(EMBED_BATCH_SIZE).enumerate() {
+            if cancelled.load(Ordering::Relaxed) {
+                return Ok((embeddings, true));
+            }
+            for (j, values) in self.embed_batch(batch).await?.into_iter().enumerate() {
+                embeddings[i * EMBED_BATCH_SIZE + j] = Some(values);
+            }
+        }
+        Ok((embeddings, false))
+    }
+
+    /// 请求一批文本的向量表示
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let model = self.model.resource_name();
+        let body = BatchEmbedContentsRequest {
+            requests: texts
+                .iter()
+                .map(|text| EmbedContentRequest {
+                    model: model.clone(),
+                    content: Content {
+                        parts: vec![Part::Text(text.clone())],
+                        role: None,
                     },
-                ],
+                })
+                .collect(),
+        };
+        let body_json = serde_json::to_string(&body)?;
+        let response = self.post(&self.method_url("batchEmbedContents"), body_json).await?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            let response: BatchEmbedContentsResponse = serde_json::from_str(&response_text)?;
+            if response.embeddings.len() != texts.len() {
+                bail!("Expected {} embeddings, got {}", texts.len(), response.embeddings.len())
+            }
+            Ok(response
+                .embeddings
+                .into_iter()
+                .map(|embedding| embedding.values)
+                .collect())
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
+    /// 基于提供的资料生成有依据的回答
+    /// 请求 generateAnswer 方法，仅 aqa 模型（`LanguageModel::Custom("models/aqa".into())`）支持
+    pub async fn generate_answer(&self, body: GenerateAnswerRequest) -> Result<GenerateAnswerResponse> {
+        let body_json = serde_json::to_string(&body)?;
+        let response = self.post(&self.method_url("generateAnswer"), body_json).await?;
+        if response.status().is_success() {
+            let response_text = self.read_text(response).await?;
+            // 解析响应内容
+            Ok(serde_json::from_str(&response_text)?)
+        } else {
+            let status = response.status();
+            let response_text = self.read_text(response).await?;
+            // 解析错误响应内容
+            bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+        }
+    }
+
+    /// 函数调用循环
+    /// 模型请求函数调用时，使用 dispatch 执行函数并将结果返回给模型，直到模型给出不含函数调用的回复
+    /// 函数调用超过 max_iterations 轮后返回 `GeminiError::ToolLoopExceeded`
+    pub async fn run_tool_loop(
+        &mut self,
+        message: Content,
+        tools: Vec<Tool>,
+        mut dispatch: impl FnMut(FunctionCall) -> serde_json::Value,
+        max_iterations: usize,
+    ) -> Result<(String, GenerateContentResponse)> {
+        let mut contents = if self.conversation {
+            self.contents.clone()
+        } else {
+            Vec::new()
+        };
+        contents.push(message);
+        let mut iterations = 0;
+        loop {
+            let mut body = self.build_request_body(contents.clone()).await?;
+            body.tools = Some(tools.clone());
+            let response = self.generate(&body).await?;
+            let Some(candidate) = response.candidates.first() else {
+                bail!("Unexpected response format")
+            };
+            let content = candidate.content.clone();
+            let function_calls: Vec<FunctionCall> = content
+                .parts
+                .iter()
+                .filter_map(|part| match part {
+                    Part::FunctionCall(call) => Some(call.clone()),
+                    _ => None,
+                })
+                .collect();
+            if function_calls.is_empty() {
+                let text = content.text_joined(&self.text_join_separator);
+                // 只有在开启历史记录时才保存整个函数调用过程
+                if self.conversation {
+                    contents.push(content);
+                    self.contents = contents;
+                }
+                return Ok((text, response));
+            }
+            if iterations == max_iterations {
+                bail!(GeminiError::ToolLoopExceeded(max_iterations))
+            }
+            iterations += 1;
+            contents.push(content);
+            let parts = function_calls
+                .into_iter()
+                .map(|call| {
+                    let name = call.name.clone();
+                    Part::FunctionResponse(FunctionResponse::new(name, dispatch(call)))
+                })
+                .collect();
+            contents.push(Content {
+                parts,
+                role: Some(Role::Function),
             });
-            let cloned_contents = self.contents.clone();
-            let body = self.build_request_body(cloned_contents);
-            let body_json = serde_json::to_string(&body)?;
+        }
+    }
 
-            // 发送 GET 请求，并添加自定义头部
-            let response = self
-                .client
-                .post(url)
-                .header("Content-Type", "application/json")
-                .body(body_json)
-                .send()
-                .await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response: GenerateContentResponse = serde_json::from_str(&response_text)?;
-                match response.candidates[0].content.parts[0].clone().clone() {
-                    Part::Text(s) => {
-                        self.contents.push(Content {
-                            role: Some(Role::Model),
-                            parts: vec![Part::Text(s.clone())],
-                        });
-                        Ok((s, response))
+    /// 流式发送消息
+    /// 返回的流逐个产出模型生成的响应片段，流结束后才会将完整回复加入历史记录
+    pub async fn send_message_stream(
+        &mut self,
+        message: Content,
+    ) -> Result<impl Stream<Item = Result<GenerateContentResponse>> + '_> {
+        self.open_stream(message, String::new()).await
+    }
+
+    /// 续传被中断的流式响应
+    /// 流中断时返回 `GeminiError::StreamInterrupted`，传入中断的那次消息以及其中已接收的文本 partial，
+    /// 将 partial 作为模型回复的开头连同历史记录一起发送，并要求模型从中断处继续
+    /// 模型不保证严格从中断处续写，但 partial 会保留在最终加入历史记录的回复中
+    pub async fn resume_stream(
+        &mut self,
+        message: Content,
+        partial: String,
+    ) -> Result<impl Stream<Item = Result<GenerateContentResponse>> + '_> {
+        self.open_stream(message, partial).await
+    }
+
+    /// 发送流式请求，partial 不为空时附带已接收的回复以及续写指令
+    async fn open_stream(
+        &mut self,
+        message: Content,
+        partial: String,
+    ) -> Result<impl Stream<Item = Result<GenerateContentResponse>> + '_> {
+        let url = format!("{}?alt=sse", self.method_url("streamGenerateContent"));
+        let contents = if self.conversation {
+            self.contents.push(message);
+            self.contents.clone()
+        } else {
+            vec![message]
+        };
+        let body_json = self.build_stream_body(&contents, &partial).await?;
+        let response = self.post(&url, body_json).await;
+        let response = match response {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                self.rollback_stream();
+                let status = response.status();
+                let response_text = self.read_text(response).await?;
+                // 解析错误响应内容
+                bail!(GeminiError::from_status_and_text(status.as_u16(), &response_text))
+            }
+            Err(e) => {
+                self.rollback_stream();
+                bail!(e)
+            }
+        };
+        let reconnects = self.stream_reconnects;
+        let state = MessageStream {
+            gemini: self,
+            response,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            contents,
+            text: partial.clone(),
+            partial,
+            merged: None,
+            reconnects,
+            reconnected: false,
+            received: 0,
+            finished: false,
+        };
+        Ok(stream::unfold(state, |mut state| async move {
+            let chunk = state.next_chunk().await?;
+            Some((chunk, state))
+        }))
+    }
+
+    /// 流式发送消息，通过 on_chunk 回调逐个接收新增的文本，流结束后返回合并后的完整响应
+    /// 完整响应包含全部文本以及最终的结束原因、token 用量，历史记录在流正常结束后保存
+    /// 可通过 `GenerateContentResponse::is_truncated` 判断回复是否因达到最大输出 token 数被截断，
+    /// 通过 `set_stream_auto_continue` 开启自动续写后，被截断的回复会自动续写直到正常结束或达到续写次数上限
+    pub async fn send_stream_collect(
+        &mut self,
+        message: Content,
+        mut on_chunk: impl FnMut(&str),
+    ) -> Result<GenerateContentResponse> {
+        let history_len = self.contents.len();
+        let mut continuations = self.stream_continuations;
+        let mut merged: Option<GenerateContentResponse> = None;
+        let mut partial = String::new();
+        loop {
+            {
+                let stream = self.open_stream(message.clone(), partial.clone()).await?;
+                let mut stream = pin!(stream);
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk?;
+                    if let Some(candidate) = chunk.candidates.first() {
+                        let text = candidate.content.text();
+                        on_chunk(&text);
+                        partial.push_str(&text);
+                    }
+                    match &mut merged {
+                        Some(merged) => merged.merge_chunk(chunk),
+                        None => merged = Some(chunk),
                     }
-                    _ => bail!("Unexpected response format"),
                 }
-            } else {
-                self.contents.pop();
-                let response_text = response.text().await?;
-                // 解析响应内容
-                let response_error: GenerateContentResponseError = serde_json::from_str(&response_text)?;
-                let error_message = response_error.error.message;
-                bail!(error_message)
             }
+            let truncated = merged.as_ref().is_some_and(GenerateContentResponse::is_truncated);
+            if !truncated || continuations == 0 {
+                break;
+            }
+            continuations -= 1;
+            // 移除被截断的这一轮，续写时重新发送请求并保存拼接后的完整回复
+            if self.conversation {
+                self.contents.truncate(history_len);
+            }
+        }
+        merged.ok_or_else(|| anyhow!("The stream ended without any response"))
+    }
+
+    /// 流式发送消息，将每个响应片段中新增的文本依次发送到 tx，流正常结束后保存历史记录
+    /// 流出错时将错误发送到 tx 后结束；接收端关闭时停止接收并移除这次请求，返回错误
+    /// 适用于生成任务与消费方通过消息传递解耦的场景，发送受通道容量限制，消费方处理较慢时会等待
+    pub async fn send_stream_to_channel(&mut self, message: Content, tx: mpsc::Sender<Result<String>>) -> Result<()> {
+        let closed = {
+            let stream = self.stream_text(message).await?;
+            let mut stream = pin!(stream);
+            let mut closed = false;
+            while let Some(text) = stream.next().await {
+                let failed = text.is_err();
+                if tx.send(text).await.is_err() {
+                    closed = !failed;
+                    break;
+                }
+                if failed {
+                    break;
+                }
+            }
+            closed
+        };
+        if closed {
+            self.rollback_stream();
+            bail!("The receiver of the stream channel was closed")
+        }
+        Ok(())
+    }
+
+    /// 流式发送消息，只产出每个响应片段中新增的文本
+    pub async fn stream_text(&mut self, message: Content) -> Result<impl Stream<Item = Result<String>> + '_> {
+        let stream = self.send_message_stream(message).await?;
+        Ok(stream.map(|chunk| {
+            chunk.map(|chunk| {
+                chunk
+                    .candidates
+                    .first()
+                    .map(|candidate| candidate.content.text())
+                    .unwrap_or_default()
+            })
+        }))
+    }
+
+    /// 流式发送消息，按句子产出回复文本，可在生成结束前开始处理第一句，如语音合成
+    /// 分句规则见 `SentenceSplitter`，流结束时产出剩余未以句末标点结尾的文本
+    pub async fn stream_sentences(&mut self, message: Content) -> Result<impl Stream<Item = Result<String>> + '_> {
+        let text = self.stream_text(message).await?;
+        Ok(SentenceSplitter::split_stream(text))
+    }
+
+    /// 构建流式请求体，partial 不为空时附带已接收的回复以及续写指令
+    async fn build_stream_body(&self, contents: &[Content], partial: &str) -> Result<String> {
+        let mut contents = contents.to_vec();
+        if !partial.is_empty() {
+            contents.push((Role::Model, partial.to_owned()).into());
+            contents.push((Role::User, RESUME_PROMPT.to_owned()).into());
+        }
+        let body = self.build_request_body(contents).await?;
+        Ok(serde_json::to_string(&body)?)
+    }
+
+    /// 流式请求失败时移除最后发送的那次用户请求
+    fn rollback_stream(&mut self) {
+        if self.conversation {
+            self.drop_failed_turn();
+        }
+    }
+}
+
+/// 流式响应状态
+/// 逐段读取 SSE 响应体，每个事件解析为一个响应片段
+struct MessageStream<'a> {
+    gemini: &'a mut Gemini,
+    response: HttpResponse<Response>,
+    buffer: Vec<u8>,
+    /// 已解析但尚未返回的响应片段
+    pending: VecDeque<GenerateContentResponse>,
+    /// 发送的历史记录，用于重连
+    contents: Vec<Content>,
+    text: String,
+    /// 自动续写时之前已接收的回复文本，记录历史时加在回复开头
+    partial: String,
+    /// 合并后的全部响应片段，流结束时将其完整内容加入历史记录
+    merged: Option<GenerateContentResponse>,
+    /// 剩余可重连次数
+    reconnects: usize,
+    reconnected: bool,
+    /// 已接收的字节数
+    received: usize,
+    finished: bool,
+}
+
+impl MessageStream<'_> {
+    /// 读取下一个响应片段，流结束时返回 None
+    async fn next_chunk(&mut self) -> Option<Result<GenerateContentResponse>> {
+        if self.finished {
+            return None;
         }
+        loop {
+            // 先返回已完整接收的响应片段
+            if let Some(mut response) = self.pending.pop_front() {
+                response.reconnected = std::mem::take(&mut self.reconnected);
+                response.requested_model = Some(self.gemini.model.resource_name());
+                if let Some(candidate) = response.candidates.first() {
+                    self.text.push_str(&candidate.content.text());
+                    if candidate.finish_reason.is_some() {
+                        self.gemini.last_finish_reason = candidate.finish_reason.clone();
+                    }
+                }
+                match &mut self.merged {
+                    Some(merged) => merged.merge_chunk(response.clone()),
+                    None => self.merged = Some(response.clone()),
+                }
+                return Some(Ok(response));
+            }
+            let parsed = match self.response.chunk().await {
+                Ok(Some(bytes)) => {
+                    self.received += bytes.len();
+                    if let Some(limit) = self.gemini.max_response_bytes.filter(|&limit| self.received > limit) {
+                        self.finished = true;
+                        self.gemini.rollback_stream();
+                        return Some(Err(GeminiError::ResponseTooLarge(limit).into()));
+                    }
+                    parse_sse_chunk(&mut self.buffer, &bytes)
+                }
+                // 响应体最后一个事件可能没有以空行结尾
+                Ok(None) if !self.buffer.is_empty() => {
+                    let parsed = parse_sse_chunk(&mut self.buffer, b"\n\n");
+                    self.buffer.clear();
+                    parsed
+                }
+                Ok(None) => {
+                    // 响应结束，将完整回复加入历史记录
+                    self.finished = true;
+                    self.record();
+                    return None;
+                }
+                Err(e) if self.reconnects > 0 => {
+                    self.reconnects -= 1;
+                    match self.reconnect().await {
+                        Ok(response) => {
+                            // 丢弃未接收完整的事件，从新的响应继续读取
+                            self.response = response;
+                            self.buffer.clear();
+                            self.reconnected = true;
+                        }
+                        Err(_) => return Some(Err(self.fail(redact_error(e).into()))),
+                    }
+                    continue;
+                }
+                Err(e) => return Some(Err(self.fail(redact_error(e).into()))),
+            };
+            match parsed {
+                Ok(responses) => self.pending.extend(responses),
+                // 流中返回的错误事件原样返回并结束流
+                Err(e) if matches!(e.downcast_ref(), Some(GeminiError::Api { .. })) => {
+                    self.finished = true;
+                    self.gemini.rollback_stream();
+                    return Some(Err(e));
+                }
+                Err(e) => return Some(Err(self.fail(e))),
+            }
+        }
+    }
+
+    /// 连同已接收的文本重新发送流式请求
+    async fn reconnect(&self) -> Result<HttpResponse<Response>> {
+        let url = format!("{}?alt=sse", self.gemini.method_url("streamGenerateContent"));
+        let body_json = self.gemini.build_stream_body(&self.contents, &self.text).await?;
+        let response = self.gemini.post(&url, body_json).await?;
+        if !response.status().is_success() {
+            bail!("Failed to reconnect the stream: {}", response.status())
+        }
+        Ok(response)
+    }
+
+    /// 流正常结束时将合并后的完整回复加入历史记录，函数调用、思考签名等非文本内容一并保留
+    fn record(&mut self) {
+        match self.merged.take().filter(|merged| !merged.candidates.is_empty()) {
+            Some(merged) => {
+                self.gemini.record_response_with_prefix(&merged, &self.partial);
+            }
+            None => self
+                .gemini
+                .push_model_turn((Role::Model, std::mem::take(&mut self.text)).into()),
+        }
+    }
+
+    /// 流中断时结束流并移除最后发送的那次用户请求
+    /// 返回携带已接收文本的 `GeminiError::StreamInterrupted`，可用于 `resume_stream`
+    fn fail(&mut self, error: anyhow::Error) -> anyhow::Error {
+        self.finished = true;
+        self.gemini.rollback_stream();
+        GeminiError::StreamInterrupted {
+            partial: std::mem::take(&mut self.text),
+            message: error.to_string(),
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_known_role_prefix() {
+        assert_eq!(strip_role_prefix("Model: Hello Reine"), "Hello Reine");
+        assert_eq!(strip_role_prefix("assistant:\nHello"), "Hello");
+        assert_eq!(strip_role_prefix("Models: are great"), "Models: are great");
+        assert_eq!(strip_role_prefix("User: Hello"), "User: Hello");
+    }
+
+    #[test]
+    fn dedup_model_turn_parts() {
+        let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        let doubled = Content {
+            parts: vec![Part::Text("Hello Reine".into()), Part::Text("Hello Reine".into())],
+            role: Some(Role::Model),
+        };
+        // 未开启历史记录时不记录
+        client.push_model_turn(doubled.clone());
+        assert!(client.contents.is_empty());
+        client.start_chat(Vec::new());
+        client.push_model_turn(doubled.clone());
+        assert_eq!(client.contents, vec![(Role::Model, "Hello Reine".to_owned()).into()]);
+        // 不同轮次中相同的回复均保留
+        client.push_model_turn(doubled);
+        assert_eq!(client.contents.len(), 2);
+    }
+
+    #[test]
+    fn record_last_candidates() -> Result<()> {
+        let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        assert!(client.last_candidates().is_none());
+        let response = parse_response(
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hello Reine"}],"role":"model"},"finishReason":"STOP","safetyRatings":[{"category":"HARM_CATEGORY_HARASSMENT","probability":"NEGLIGIBLE"}]}]}"#,
+            false,
+        )?;
+        client.record_response(&response);
+        let candidates = client.last_candidates().unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].content.text(), "Hello Reine");
+        Ok(())
+    }
+
+    #[test]
+    fn keep_failed_turns() {
+        let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        client.start_chat(vec![(Role::User, "My Name is Reine".to_owned()).into()]);
+        client.drop_failed_turn();
+        assert!(client.contents.is_empty());
+        client.set_keep_failed_turns(true);
+        client.contents.push((Role::User, "My Name is Reine".to_owned()).into());
+        client.drop_failed_turn();
+        assert_eq!(client.contents.len(), 1);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "image_analysis")]
+    async fn resolve_image_url_on_send() {
+        let content = Content {
+            parts: vec![
+                Part::Text("分析一下这张图片".into()),
+                Part::image_url("http://127.0.0.1:1/a.png"),
+            ],
+            role: Some(Role::User),
+        };
+        // 未下载的图片以 imageUrl 序列化，可保存后恢复
+        let json = serde_json::to_value(&content).unwrap();
+        assert_eq!(
+            json["parts"][1],
+            serde_json::json!({"imageUrl": "http://127.0.0.1:1/a.png"})
+        );
+        assert_eq!(serde_json::from_value::<Content>(json).unwrap(), content);
+        let body = GeminiRequestBody {
+            contents: vec![content.clone()],
+            ..Default::default()
+        };
+        assert_eq!(body.fingerprint().len(), 16);
+        let client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        assert!(client.build_request_body(vec![content]).await.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "image_analysis")]
+    fn inline_data_over_request_limit() {
+        let inline = |size: usize| Part::InlineData {
+            mime_type: "image/png".into(),
+            data: "A".repeat(size),
+            video_metadata: None,
+        };
+        let mut contents = vec![Content {
+            parts: vec![inline(INLINE_DATA_LIMIT / 2), Part::Text("分析一下这张图片".into())],
+            role: Some(Role::User),
+        }];
+        assert!(check_inline_data_size(&contents).is_ok());
+        contents.push(Content {
+            parts: vec![inline(INLINE_DATA_LIMIT / 2 + 1)],
+            role: Some(Role::User),
+        });
+        let err = check_inline_data_size(&contents).unwrap_err();
+        assert!(err.to_string().contains("Files API"));
+    }
+
+    #[test]
+    fn vertex_endpoint_url_and_auth() -> Result<()> {
+        let client = Gemini::vertex(
+            "my-project".into(),
+            "us-central1".into(),
+            LanguageModel::Gemini1_5Flash,
+            "token".into(),
+        );
+        assert_eq!(
+            client.method_url("generateContent"),
+            "https://us-central1-aiplatform.googleapis.com/v1/projects/my-project/locations/us-central1/publishers/google/models/gemini-1.5-flash:generateContent"
+        );
+        let (url, headers) = authorize(&client.method_url("generateContent"), "token", &client.headers, true)?;
+        assert!(!url.contains("key="));
+        assert_eq!(headers[AUTHORIZATION], "Bearer token");
+        // 非 Vertex AI 时仍在地址中附带 API key
+        let (url, headers) = authorize("https://example.com/a?alt=sse", "key", &HeaderMap::new(), false)?;
+        assert_eq!(url, "https://example.com/a?alt=sse&key=key");
+        assert!(headers.get(AUTHORIZATION).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_vertex_file_upload() {
+        let client = Gemini::vertex(
+            "my-project".into(),
+            "us-central1".into(),
+            LanguageModel::Gemini1_5Flash,
+            "token".into(),
+        );
+        // 访问令牌不会以 key 参数发送到 Files API
+        let err = client
+            .upload_file("./file_type_rust.png".into(), String::new(), |_, _| {})
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Vertex AI"));
+    }
+
+    #[test]
+    fn create_client_from_config() -> Result<()> {
+        let config: GeminiConfig = serde_json::from_str(
+            r#"{
+                "key": "key",
+                "model": "gemini-1.5-pro",
+                "base_url": "https://gateway.example.com/v1beta",
+                "timeout": 30,
+                "system_instruction": "你是 Reine",
+                "options": {"temperature": 0.2, "maxOutputTokens": 1024}
+            }"#,
+        )?;
+        assert_eq!(config.timeout, Some(Duration::from_secs(30)));
+        let client = Gemini::from_config(config)?;
+        assert_eq!(client.key, "key");
+        assert_eq!(client.model, LanguageModel::Gemini1_5Pro);
+        assert_eq!(client.system_instruction.as_deref(), Some("你是 Reine"));
+        assert_eq!(client.options.temperature, Some(0.2));
+        assert_eq!(client.options.max_output_tokens, Some(1024));
+        assert_eq!(
+            client.method_url("generateContent"),
+            "https://gateway.example.com/v1beta/models/gemini-1.5-pro:generateContent"
+        );
+        // 只有 key 与 model 为必填项
+        let config: GeminiConfig = serde_json::from_str(r#"{"key": "key", "model": "models/my-tuned-model"}"#)?;
+        let client = Gemini::from_config(config)?;
+        assert_eq!(client.model, LanguageModel::Custom("models/my-tuned-model".into()));
+        assert_eq!(
+            client.method_url("generateContent"),
+            format!("{}models/my-tuned-model:generateContent", GEMINI_API_URL)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn attach_referer_header() -> Result<()> {
+        let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        client.set_referer("https://example.com/".into())?;
+        let (_, headers) = authorize(&client.method_url("generateContent"), "key", &client.headers, false)?;
+        assert_eq!(headers[REFERER], "https://example.com/");
+        assert!(client.set_referer("https://example.com/\n".into()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cap_max_output_tokens() {
+        assert_eq!(effective_output_limit(Some(8192), 65536), 8192);
+        assert_eq!(effective_output_limit(Some(100_000), 65536), 65536);
+        assert_eq!(effective_output_limit(None, 65536), 65536);
+    }
+
+    #[test]
+    fn fork_and_merge_history() {
+        let mut main = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        main.start_chat(vec![
+            (Role::User, "My Name is Reine".to_owned()).into(),
+            (Role::Model, "Hello Reine".to_owned()).into(),
+        ]);
+        main.last_finish_reason = Some(FinishReason::Stop);
+        let mut branch = main.fork();
+        assert!(branch.last_finish_reason.is_none());
+        assert_eq!(branch.contents, main.contents);
+        branch.contents.push((Role::User, "Who am I".to_owned()).into());
+        branch.contents.push((Role::Model, "You are Reine".to_owned()).into());
+        // 分叉前的对话只保留一份
+        main.merge_history(&branch).unwrap();
+        assert_eq!(main.history_len(), 4);
+        // 连续两条用户消息时合并失败且不修改历史记录
+        let mut other = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        other.start_chat(vec![(Role::User, "Hi".to_owned()).into()]);
+        main.contents.push((Role::User, "Hello".to_owned()).into());
+        assert!(main.merge_history(&other).is_err());
+        assert_eq!(main.history_len(), 5);
+    }
+
+    #[test]
+    fn lenient_response_parsing() {
+        let reply =
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hello Reine"}],"role":"model"},"unknownField":1}]}"#;
+        assert!(parse_response(reply, false).is_ok());
+        // 代理网关在 JSON 前后附加的多余内容
+        let with_trailing = format!("\u{feff}{}\n\ndata: [DONE]\n", reply);
+        let err = parse_response(&with_trailing, false).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(GeminiError::InvalidResponse { .. })));
+        let response = parse_response(&with_trailing, true).unwrap();
+        assert_eq!(response.candidates[0].content.text(), "Hello Reine");
+        assert!(parse_response("", true).is_err());
+    }
+
+    #[test]
+    fn export_openai_messages() {
+        let mut client = Gemini::with_system_instruction("".into(), LanguageModel::Gemini1_5Flash, "你是 Reine".into());
+        client.start_chat(vec![
+            (Role::User, "My Name is Reine".to_owned()).into(),
+            Content {
+                role: Some(Role::Model),
+                parts: vec![Part::FunctionCall(FunctionCall {
+                    name: "greet".into(),
+                    args: None,
+                    thought_signature: None,
+                })],
+            },
+            Content {
+                role: Some(Role::Function),
+                parts: vec![Part::FunctionResponse(FunctionResponse::new(
+                    "greet".into(),
+                    serde_json::json!("Hello"),
+                ))],
+            },
+            (Role::Model, "Hello Reine".to_owned()).into(),
+        ]);
+        assert_eq!(
+            client.to_openai_messages(),
+            vec![
+                serde_json::json!({"role": "system", "content": "你是 Reine"}),
+                serde_json::json!({"role": "user", "content": "My Name is Reine"}),
+                serde_json::json!({"role": "assistant", "content": "Hello Reine"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn import_openai_messages() {
+        let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        client
+            .from_openai_messages(&[
+                serde_json::json!({"role": "system", "content": "你是 Reine"}),
+                serde_json::json!({"role": "user", "content": [{"type": "text", "text": "My Name is Reine"}]}),
+                serde_json::json!({"role": "assistant", "content": null, "tool_calls": []}),
+                serde_json::json!({"role": "tool", "content": "Hello"}),
+                serde_json::json!({"role": "assistant", "content": "Hello Reine"}),
+            ])
+            .unwrap();
+        assert!(client.conversation);
+        assert_eq!(
+            client.to_openai_messages(),
+            vec![
+                serde_json::json!({"role": "system", "content": "你是 Reine"}),
+                serde_json::json!({"role": "user", "content": "My Name is Reine"}),
+                serde_json::json!({"role": "assistant", "content": "Hello Reine"}),
+            ]
+        );
+        assert!(client
+            .from_openai_messages(&[serde_json::json!({"role": "developer", "content": "Hi"})])
+            .is_err());
+    }
+
+    #[test]
+    fn pair_conversation_turns() {
+        let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+        client.start_chat(vec![
+            (Role::User, "My Name is Reine".to_owned()).into(),
+            (Role::Model, "Hello Reine".to_owned()).into(),
+            (Role::User, "Who am I".to_owned()).into(),
+        ]);
+        let turns: Vec<_> = client
+            .turns()
+            .map(|(user, model)| (user.text(), model.map(Content::text)))
+            .collect();
+        assert_eq!(
+            turns,
+            [
+                ("My Name is Reine".to_owned(), Some("Hello Reine".to_owned())),
+                ("Who am I".to_owned(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_defaults_of_model() {
+        let model: Model = serde_json::from_value(serde_json::json!({
+            "name": "models/gemini-1.5-flash",
+            "version": "001",
+            "displayName": "Gemini 1.5 Flash",
+            "description": "",
+            "inputTokenLimit": 1000000,
+            "outputTokenLimit": 8192,
+            "supportedGenerationMethods": ["generateContent"],
+            "temperature": 1.0,
+            "topP": 0.95,
+        }))
+        .unwrap();
+        let mut options = GenerationConfig {
+            temperature: Some(0.2),
+            top_k: Some(40),
+            ..Default::default()
+        };
+        apply_model_defaults(&mut options, &model);
+        assert_eq!(options.temperature, Some(1.0));
+        assert_eq!(options.top_p, Some(0.95));
+        // 模型未提供的参数保持不变
+        assert_eq!(options.top_k, Some(40));
+    }
+
+    #[test]
+    fn list_generate_content_model_choices() {
+        let response: ModelsResponse = serde_json::from_value(serde_json::json!({
+            "models": [
+                {
+                    "name": "models/gemini-1.5-flash",
+                    "version": "001",
+                    "displayName": "Gemini 1.5 Flash",
+                    "description": "",
+                    "inputTokenLimit": 1000000,
+                    "outputTokenLimit": 8192,
+                    "supportedGenerationMethods": ["generateContent", "countTokens"],
+                },
+                {
+                    "name": "models/text-embedding-004",
+                    "version": "004",
+                    "displayName": "Text Embedding 004",
+                    "description": "",
+                    "inputTokenLimit": 2048,
+                    "outputTokenLimit": 1,
+                    "supportedGenerationMethods": ["embedContent"],
+                },
+                {
+                    "name": "models/gemini-2.0-flash",
+                    "version": "2.0",
+                    "displayName": "Gemini 2.0 Flash",
+                    "description": "",
+                    "inputTokenLimit": 1048576,
+                    "outputTokenLimit": 8192,
+                    "supportedGenerationMethods": ["generateContent"],
+                },
+            ],
+            "nextPageToken": "next",
+        }))
+        .unwrap();
+        assert_eq!(
+            model_choices(response.models),
+            vec![
+                (LanguageModel::Gemini1_5Flash, "Gemini 1.5 Flash".to_owned()),
+                (
+                    LanguageModel::Custom("models/gemini-2.0-flash".into()),
+                    "Gemini 2.0 Flash".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(
+            models_page_url("key", Some("next")),
+            format!("{}models?pageSize=1000&key=key&pageToken=next", GEMINI_API_URL)
+        );
     }
 }
@@ -0,0 +1,124 @@
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use reqwest::{header::HeaderMap, StatusCode};
+
+use crate::utils::redact::redact_error;
+
+/// HTTP 传输层，发送非流式的 POST 请求并返回响应的状态码以及响应体文本
+/// 默认直接使用客户端内部的 reqwest 客户端，可通过 `Gemini::set_transport` 注入其他实现，例如在测试中返回固定的 JSON
+/// 文件上传不经过传输层，流式请求经过传输层时整个响应体作为一段返回
+pub trait HttpTransport: Send + Sync {
+    /// 发送 POST 请求，url 中已包含 API key
+    fn post<'a>(&'a self, url: &'a str, headers: &'a HeaderMap, body: String) -> BoxFuture<'a, Result<(u16, String)>>;
+}
+
+impl HttpTransport for reqwest::Client {
+    fn post<'a>(&'a self, url: &'a str, headers: &'a HeaderMap, body: String) -> BoxFuture<'a, Result<(u16, String)>> {
+        Box::pin(async move {
+            let response = reqwest::Client::post(self, url)
+                .headers(headers.clone())
+                .body(body)
+                .send()
+                .await
+                .map_err(redact_error)?;
+            let status = response.status().as_u16();
+            Ok((status, response.text().await.map_err(redact_error)?))
+        })
+    }
+}
+
+/// 请求的响应，来自 reqwest 客户端或注入的传输层
+pub(crate) enum HttpResponse<R> {
+    Reqwest(R),
+    Transport { status: StatusCode, body: Vec<u8> },
+}
+
+impl<R> From<R> for HttpResponse<R> {
+    fn from(response: R) -> Self {
+        HttpResponse::Reqwest(response)
+    }
+}
+
+impl HttpResponse<reqwest::Response> {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            HttpResponse::Reqwest(response) => response.status(),
+            HttpResponse::Transport { status, .. } => *status,
+        }
+    }
+
+    /// 读取整个响应体文本
+    pub(crate) async fn text(self) -> reqwest::Result<String> {
+        match self {
+            HttpResponse::Reqwest(response) => response.text().await,
+            HttpResponse::Transport { body, .. } => Ok(String::from_utf8_lossy(&body).into_owned()),
+        }
+    }
+
+    /// 读取下一段响应体，传输层的响应体一次性返回
+    pub(crate) async fn chunk(&mut self) -> reqwest::Result<Option<Vec<u8>>> {
+        match self {
+            HttpResponse::Reqwest(response) => Ok(response.chunk().await?.map(|bytes| bytes.to_vec())),
+            HttpResponse::Transport { body, .. } if body.is_empty() => Ok(None),
+            HttpResponse::Transport { body, .. } => Ok(Some(std::mem::take(body))),
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+pub mod blocking {
+    use std::io::{self, Read};
+
+    use super::*;
+
+    /// 阻塞式 HTTP 传输层，发送非流式的 POST 请求并返回响应的状态码以及响应体文本
+    /// 默认直接使用客户端内部的 reqwest 客户端，可通过 `blocking::Gemini::set_transport` 注入其他实现
+    /// 文件上传不经过传输层，流式请求经过传输层时整个响应体作为一段返回
+    pub trait HttpTransport: Send + Sync {
+        /// 发送 POST 请求，url 中已包含 API key
+        fn post(&self, url: &str, headers: &HeaderMap, body: String) -> Result<(u16, String)>;
+    }
+
+    impl HttpTransport for reqwest::blocking::Client {
+        fn post(&self, url: &str, headers: &HeaderMap, body: String) -> Result<(u16, String)> {
+            let response = reqwest::blocking::Client::post(self, url)
+                .headers(headers.clone())
+                .body(body)
+                .send()
+                .map_err(redact_error)?;
+            let status = response.status().as_u16();
+            Ok((status, response.text().map_err(redact_error)?))
+        }
+    }
+
+    impl HttpResponse<reqwest::blocking::Response> {
+        pub(crate) fn status(&self) -> StatusCode {
+            match self {
+                HttpResponse::Reqwest(response) => response.status(),
+                HttpResponse::Transport { status, .. } => *status,
+            }
+        }
+
+        /// 读取整个响应体文本
+        pub(crate) fn text(self) -> reqwest::Result<String> {
+            match self {
+                HttpResponse::Reqwest(response) => response.text(),
+                HttpResponse::Transport { body, .. } => Ok(String::from_utf8_lossy(&body).into_owned()),
+            }
+        }
+    }
+
+    impl Read for HttpResponse<reqwest::blocking::Response> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self {
+                HttpResponse::Reqwest(response) => response.read(buf),
+                HttpResponse::Transport { body, .. } => {
+                    let n = body.len().min(buf.len());
+                    buf[..n].copy_from_slice(&body[..n]);
+                    body.drain(..n);
+                    Ok(n)
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use std::fmt;
 
 use serde::{Deserialize, Serialize};
-#[derive(Clone, Default, Serialize, Deserialize)]
+
+use crate::body::{Content, Part};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LanguageModel {
     #[serde(rename = "gemini-1.0-pro")]
     Gemini1_0Pro,
@@ -24,6 +27,22 @@ impl fmt::Display for LanguageModel {
     }
 }
 
+impl LanguageModel {
+    /// 获取模型的资源名称，如 "models/gemini-1.5-flash"
+    /// Custom 中缺少 "models/" 前缀时自动补全，已带有其他资源前缀（如 "tunedModels/"）时保持不变
+    pub fn resource_name(&self) -> String {
+        match self {
+            LanguageModel::Custom(s) if !s.contains('/') => format!("models/{s}"),
+            _ => self.to_string(),
+        }
+    }
+
+    /// 规范化模型，补全 Custom 中缺少的 "models/" 前缀，已知模型转换为对应的枚举值
+    pub fn normalize(&self) -> Self {
+        self.resource_name().into()
+    }
+}
+
 /// 实现 String 与 LanguageModel 之间的转换
 impl From<String> for LanguageModel {
     fn from(val: String) -> Self {
@@ -35,3 +54,42 @@ impl From<String> for LanguageModel {
         }
     }
 }
+
+/// 请求成功后从模型回复中提取返回文本的方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtractStrategy {
+    /// 第一个文本部分
+    FirstText,
+    /// 所有文本部分依次拼接
+    #[default]
+    ConcatenatedText,
+    /// 第一部分，非文本部分返回其 JSON 序列化结果
+    FirstPart,
+}
+
+impl ExtractStrategy {
+    /// 按提取方式从回复内容中提取文本，没有可提取的内容时返回空文本
+    pub fn extract(&self, content: &Content) -> String {
+        self.extract_joined(content, "")
+    }
+
+    /// 按提取方式从回复内容中提取文本，拼接多个文本部分时使用指定的分隔符
+    pub fn extract_joined(&self, content: &Content, separator: &str) -> String {
+        match self {
+            ExtractStrategy::FirstText => content
+                .parts
+                .iter()
+                .find_map(|part| match part {
+                    Part::Text(s) => Some(s.clone()),
+                    _ => None,
+                })
+                .unwrap_or_default(),
+            ExtractStrategy::ConcatenatedText => content.text_joined(separator),
+            ExtractStrategy::FirstPart => match content.parts.first() {
+                Some(Part::Text(s)) => s.clone(),
+                Some(part) => serde_json::to_string(part).unwrap_or_default(),
+                None => String::new(),
+            },
+        }
+    }
+}
@@ -1,9 +1,22 @@
+use std::time::Duration;
+
 use anyhow::{bail, Result};
 
+use crate::{body::error::is_retryable_status, utils::mime::guess_mime};
+
+/// 重试下载网络图片前的初始等待时间
+const IMAGE_DOWNLOAD_BACKOFF: Duration = Duration::from_millis(500);
+/// 重试下载网络图片前的最长等待时间
+const IMAGE_DOWNLOAD_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 /// 猜测图片类型
 pub fn guess_image_format(buffer: &[u8]) -> String {
-    let img = image::guess_format(buffer).unwrap();
-    match img {
+    sniff_image_format(buffer).unwrap_or("unknown").into()
+}
+
+/// 根据图片内容猜测图片类型，无法识别时返回 None
+fn sniff_image_format(buffer: &[u8]) -> Option<&'static str> {
+    let mime_type = match image::guess_format(buffer).ok()? {
         image::ImageFormat::Png => "image/png",
         image::ImageFormat::Jpeg => "image/jpeg",
         image::ImageFormat::Gif => "image/gif",
@@ -19,67 +32,178 @@ pub fn guess_image_format(buffer: &[u8]) -> String {
         image::ImageFormat::Farbfeld => "image/x-farbfeld",
         image::ImageFormat::Avif => "image/avif",
         image::ImageFormat::Qoi => "image/x-qoi",
-        _ => "unknown",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+/// 根据文件扩展名猜测图片类型，网络图片使用 URL 的路径部分
+pub fn guess_image_format_from_path(image_path: &str) -> Option<&'static str> {
+    let mime_type = image::ImageFormat::from_extension(path_extension(image_path)?)?.to_mime_type();
+    (mime_type != "application/octet-stream").then_some(mime_type)
+}
+
+/// 获取文件的扩展名，网络图片使用 URL 的路径部分
+fn path_extension(image_path: &str) -> Option<String> {
+    let path = if image_path.starts_with("https://") || image_path.starts_with("http://") {
+        reqwest::Url::parse(image_path).ok()?.path().to_owned()
+    } else {
+        image_path.to_owned()
+    };
+    Some(std::path::Path::new(&path).extension()?.to_str()?.to_owned())
+}
+
+/// 获取图片类型，优先根据图片内容猜测，失败时根据文件扩展名猜测
+/// 同时识别 `guess_mime` 支持的其他媒体类型，使内联数据与 Files API 上传的类型判断一致
+pub fn image_mime_type(buffer: &[u8], image_path: &str) -> Result<String> {
+    let mime_type = sniff_image_format(buffer)
+        .map(Into::into)
+        .or_else(|| guess_mime(buffer, path_extension(image_path).as_deref()))
+        .or_else(|| guess_image_format_from_path(image_path).map(Into::into));
+    match mime_type {
+        Some(mime_type) => Ok(mime_type),
+        None => bail!("Failed to detect image format: {}", image_path),
+    }
+}
+
+/// 解码 base64 字符串，标准编码解码失败时尝试 URL 安全编码
+pub fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    match general_purpose::STANDARD.decode(data) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => general_purpose::URL_SAFE
+            .decode(data)
+            .map_err(|_| anyhow::anyhow!("Invalid base64 data: {}", e)),
     }
-    .into()
 }
 
 /// 猜测图片类型以及返回图片对应base64编码字符串
 pub async fn get_image_type_and_base64_string(image_path: String) -> Result<(String, String)> {
     use base64::{engine::general_purpose, Engine as _};
-    use image::EncodableLayout;
     use std::{fs::File, io::Read};
 
-    use crate::utils::image::guess_image_format;
-
-    let client = reqwest::Client::new();
+    use crate::utils::image::image_mime_type;
 
     if image_path.starts_with("https://") || image_path.starts_with("http://") {
-        let response = client.get(image_path).send().await?;
-        if response.status().is_success() {
-            let bytes = response.bytes().await?; // 读取整个响应体为字节
-            let base64_string = general_purpose::STANDARD.encode(&bytes);
-            Ok((guess_image_format(bytes.as_bytes()), base64_string))
-        } else {
-            bail!("Failed to download image, status: {}", response.status());
-        }
+        download_image(&reqwest::Client::new(), &image_path).await
     } else {
         let mut buffer = Vec::new();
-        let mut file = File::open(image_path)?;
+        let mut file = File::open(&image_path)?;
         file.read_to_end(&mut buffer)?;
         let base64_string = general_purpose::STANDARD.encode(&buffer);
-        Ok((guess_image_format(buffer.as_slice()), base64_string))
+        Ok((image_mime_type(buffer.as_slice(), &image_path)?, base64_string))
     }
 }
 
+/// 使用给定的客户端下载网络图片，返回图片类型以及对应的 base64 编码字符串
+pub async fn download_image(client: &reqwest::Client, url: &str) -> Result<(String, String)> {
+    download_image_with_retries(client, url, 0).await
+}
+
+/// 下载网络图片，遇到网络错误或可重试的状态码（408、429、5xx，与请求的重试判断一致）时按指数退避重试，最多重试 retries 次
+pub async fn download_image_with_retries(
+    client: &reqwest::Client,
+    url: &str,
+    retries: usize,
+) -> Result<(String, String)> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let mut attempt = 0;
+    let response = loop {
+        let result = client.get(url).send().await;
+        let retryable = match &result {
+            Ok(response) => is_retryable_status(response.status().as_u16()),
+            Err(_) => true,
+        };
+        if !retryable || attempt == retries {
+            break result?;
+        }
+        tokio::time::sleep(download_backoff(attempt)).await;
+        attempt += 1;
+    };
+    if response.status().is_success() {
+        let bytes = response.bytes().await?; // 读取整个响应体为字节
+        let base64_string = general_purpose::STANDARD.encode(&bytes);
+        Ok((image_mime_type(&bytes, url)?, base64_string))
+    } else {
+        bail!("Failed to download image, status: {}", response.status());
+    }
+}
+
+/// 第 attempt 次重试下载前的等待时间，从 `IMAGE_DOWNLOAD_BACKOFF` 开始每次翻倍，最长为 `IMAGE_DOWNLOAD_MAX_BACKOFF`
+fn download_backoff(attempt: usize) -> Duration {
+    IMAGE_DOWNLOAD_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt as u32))
+        .min(IMAGE_DOWNLOAD_MAX_BACKOFF)
+}
+
 pub mod blocking {
     use super::*;
 
     /// 猜测图片类型以及返回图片对应base64编码字符串
     pub fn get_image_type_and_base64_string(image_path: String) -> Result<(String, String)> {
         use base64::{engine::general_purpose, Engine as _};
-        use image::EncodableLayout;
         use std::{fs::File, io::Read};
 
-        use crate::utils::image::guess_image_format;
-
-        let client = reqwest::blocking::Client::new();
+        use crate::utils::image::image_mime_type;
 
         if image_path.starts_with("https://") || image_path.starts_with("http://") {
-            let response = client.get(image_path).send()?;
-            if response.status().is_success() {
-                let bytes = response.bytes()?; // 读取整个响应体为字节
-                let base64_string = general_purpose::STANDARD.encode(&bytes);
-                Ok((guess_image_format(bytes.as_bytes()), base64_string))
-            } else {
-                bail!("Failed to download image, status: {}", response.status());
-            }
+            download_image(&reqwest::blocking::Client::new(), &image_path)
         } else {
             let mut buffer = Vec::new();
-            let mut file = File::open(image_path)?;
+            let mut file = File::open(&image_path)?;
             file.read_to_end(&mut buffer)?;
             let base64_string = general_purpose::STANDARD.encode(&buffer);
-            Ok((guess_image_format(buffer.as_slice()), base64_string))
+            Ok((image_mime_type(buffer.as_slice(), &image_path)?, base64_string))
+        }
+    }
+
+    /// 使用给定的客户端下载网络图片，返回图片类型以及对应的 base64 编码字符串
+    pub fn download_image(client: &reqwest::blocking::Client, url: &str) -> Result<(String, String)> {
+        download_image_with_retries(client, url, 0)
+    }
+
+    /// 下载网络图片，遇到网络错误或可重试的状态码（408、429、5xx，与请求的重试判断一致）时按指数退避重试，最多重试 retries 次
+    pub fn download_image_with_retries(
+        client: &reqwest::blocking::Client,
+        url: &str,
+        retries: usize,
+    ) -> Result<(String, String)> {
+        use base64::{engine::general_purpose, Engine as _};
+
+        let mut attempt = 0;
+        let response = loop {
+            let result = client.get(url).send();
+            let retryable = match &result {
+                Ok(response) => is_retryable_status(response.status().as_u16()),
+                Err(_) => true,
+            };
+            if !retryable || attempt == retries {
+                break result?;
+            }
+            std::thread::sleep(download_backoff(attempt));
+            attempt += 1;
+        };
+        if response.status().is_success() {
+            let bytes = response.bytes()?; // 读取整个响应体为字节
+            let base64_string = general_purpose::STANDARD.encode(&bytes);
+            Ok((image_mime_type(&bytes, url)?, base64_string))
+        } else {
+            bail!("Failed to download image, status: {}", response.status());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cap_download_backoff() {
+        assert_eq!(download_backoff(0), Duration::from_millis(500));
+        assert_eq!(download_backoff(3), Duration::from_secs(4));
+        assert_eq!(download_backoff(6), IMAGE_DOWNLOAD_MAX_BACKOFF);
+        assert_eq!(download_backoff(usize::MAX), IMAGE_DOWNLOAD_MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,93 @@
+/// 根据文件内容猜测媒体类型，无法识别时根据扩展名猜测，涵盖图片、音频、视频与文档
+/// 扩展名不区分大小写，可带或不带开头的 `.`，两者都无法识别时返回 None
+pub fn guess_mime(bytes: &[u8], hint_extension: Option<&str>) -> Option<String> {
+    sniff_mime(bytes)
+        .or_else(|| hint_extension.and_then(mime_from_extension))
+        .map(Into::into)
+}
+
+/// 根据文件开头的魔数猜测媒体类型
+fn sniff_mime(bytes: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| bytes.get(offset..offset + magic.len()) == Some(magic);
+    let mime_type = match bytes {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "image/gif",
+        [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => "image/tiff",
+        [0, 0, 1, 0, ..] => "image/x-icon",
+        [b'B', b'M', ..] => "image/bmp",
+        [b'R', b'I', b'F', b'F', ..] if at(8, b"WEBP") => "image/webp",
+        [b'R', b'I', b'F', b'F', ..] if at(8, b"WAVE") => "audio/wav",
+        [b'R', b'I', b'F', b'F', ..] if at(8, b"AVI ") => "video/x-msvideo",
+        [b'F', b'O', b'R', b'M', ..] if at(8, b"AIFF") => "audio/aiff",
+        _ if at(4, b"ftyp") => match bytes.get(8..12)? {
+            b"avif" | b"avis" => "image/avif",
+            b"heic" | b"heix" | b"mif1" => "image/heic",
+            b"M4A " => "audio/mp4",
+            b"qt  " => "video/quicktime",
+            brand if brand.starts_with(b"3g") => "video/3gpp",
+            _ => "video/mp4",
+        },
+        [b'I', b'D', b'3', ..] => "audio/mpeg",
+        // ADTS 帧头的 layer 为 0 时是 AAC，否则是 MP3
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => "audio/aac",
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => "audio/mpeg",
+        [b'O', b'g', b'g', b'S', ..] => "audio/ogg",
+        [b'f', b'L', b'a', b'C', ..] => "audio/flac",
+        [0x1A, 0x45, 0xDF, 0xA3, ..] if bytes.windows(4).take(64).any(|w| w == b"webm") => "video/webm",
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => "video/x-matroska",
+        [b'F', b'L', b'V', ..] => "video/x-flv",
+        [0, 0, 1, 0xBA, ..] => "video/mpeg",
+        [0x30, 0x26, 0xB2, 0x75, ..] => "video/x-ms-wmv",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        [b'{', b'\\', b'r', b't', b'f', ..] => "text/rtf",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+/// 根据扩展名猜测媒体类型
+fn mime_from_extension(extension: &str) -> Option<&'static str> {
+    let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+    let mime_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        "ico" => "image/x-icon",
+        "avif" => "image/avif",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "aif" | "aiff" => "audio/aiff",
+        "aac" => "audio/aac",
+        "ogg" | "oga" => "audio/ogg",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "mp4" | "m4v" => "video/mp4",
+        "mpeg" | "mpg" => "video/mpeg",
+        "mov" => "video/quicktime",
+        "avi" => "video/x-msvideo",
+        "flv" => "video/x-flv",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "wmv" => "video/x-ms-wmv",
+        "3gp" | "3gpp" => "video/3gpp",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "md" => "text/md",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "xml" => "text/xml",
+        "rtf" => "text/rtf",
+        "js" => "text/javascript",
+        "py" => "text/x-python",
+        "json" => "application/json",
+        _ => return None,
+    };
+    Some(mime_type)
+}
@@ -1,2 +1,6 @@
 #[cfg(feature = "image_analysis")]
 pub mod image;
+pub mod mime;
+pub mod redact;
+pub mod sse;
+pub mod stream;
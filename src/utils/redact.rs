@@ -0,0 +1,30 @@
+use reqwest::Url;
+
+/// 将文本中 `key=` 之后的 API key 替换为 `***`，用于打印日志前隐藏密钥
+pub fn redact_key(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find("key=") {
+        let (head, tail) = rest.split_at(pos + "key=".len());
+        redacted.push_str(head);
+        let end = tail
+            .find(|c: char| matches!(c, '&' | '#' | '"' | '\'' | ')' | '>') || c.is_whitespace())
+            .unwrap_or(tail.len());
+        if end > 0 {
+            redacted.push_str("***");
+        }
+        rest = &tail[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// 隐藏 reqwest 错误中请求地址携带的 API key
+pub(crate) fn redact_error(mut error: reqwest::Error) -> reqwest::Error {
+    if let Some(url) = error.url_mut() {
+        if let Ok(redacted) = Url::parse(&redact_key(url.as_str())) {
+            *url = redacted;
+        }
+    }
+    error
+}
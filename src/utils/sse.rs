@@ -0,0 +1,146 @@
+use anyhow::{bail, Result};
+
+use crate::body::{error::GeminiError, response::GenerateContentResponse};
+
+/// 解析流式响应（SSE）的一段字节
+/// 字节先追加到缓冲区，再从缓冲区取出所有以空行结尾的完整事件，每个事件的 `data:` 行拼接后解析为一个响应片段
+/// 未接收完整的事件留在缓冲区中，与下一段字节一起解析，响应结束时传入 `b"\n\n"` 以解析最后一个未以空行结尾的事件
+/// 生成过程中返回的错误事件（如 `{"error": {...}}`）解析为 `GeminiError::Api`，错误之前的响应片段先正常返回，
+/// 错误事件留在缓冲区中，在下一次解析时返回
+pub fn parse_sse_chunk(buffer: &mut Vec<u8>, bytes: &[u8]) -> Result<Vec<GenerateContentResponse>> {
+    buffer.extend_from_slice(bytes);
+    let mut responses = Vec::new();
+    while let Some(end) = event_end(buffer) {
+        let data = event_data(&String::from_utf8_lossy(&buffer[..end]));
+        if let Some(error) = data.as_deref().and_then(error_event) {
+            if !responses.is_empty() {
+                return Ok(responses);
+            }
+            buffer.drain(..end);
+            bail!(error)
+        }
+        buffer.drain(..end);
+        if let Some(data) = data {
+            responses.push(serde_json::from_str(&data)?);
+        }
+    }
+    Ok(responses)
+}
+
+/// 将错误事件的内容解析为错误，不是错误事件时返回 None
+fn error_event(data: &str) -> Option<GeminiError> {
+    if !data.contains("\"error\"") {
+        return None;
+    }
+    GeminiError::from_response_text(data).ok()
+}
+
+/// 查找缓冲区中第一个完整事件的结束位置，即第一个空行之后
+fn event_end(buffer: &[u8]) -> Option<usize> {
+    let mut line_start = 0;
+    for (i, &byte) in buffer.iter().enumerate() {
+        if byte == b'\n' {
+            if matches!(&buffer[line_start..i], b"" | b"\r") {
+                return Some(i + 1);
+            }
+            line_start = i + 1;
+        }
+    }
+    None
+}
+
+/// 拼接事件中所有 `data:` 行的内容，多行之间以换行符连接，没有 `data:` 行时返回 None
+fn event_data(event: &str) -> Option<String> {
+    let lines: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &str = concat!(
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"你好\"}],\"role\":\"model\"},\"index\":0}]}\r\n\r\n",
+        ": keep-alive\r\n\r\n",
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\", Reine\"}],\"role\":\"model\"},\n",
+        "data: \"finishReason\":\"STOP\",\"index\":0}]}\n\n",
+    );
+
+    fn texts(responses: &[GenerateContentResponse]) -> Vec<String> {
+        responses.iter().map(|r| r.candidates[0].content.text()).collect()
+    }
+
+    #[test]
+    fn parse_whole_stream() -> Result<()> {
+        let mut buffer = Vec::new();
+        let responses = parse_sse_chunk(&mut buffer, STREAM.as_bytes())?;
+        assert_eq!(texts(&responses), ["你好", ", Reine"]);
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_stream_split_at_every_byte() -> Result<()> {
+        // 逐字节传入，覆盖在多字节字符、`\r\n` 以及 `data:` 前缀中间截断的情况
+        let mut buffer = Vec::new();
+        let mut responses = Vec::new();
+        for byte in STREAM.as_bytes() {
+            responses.extend(parse_sse_chunk(&mut buffer, std::slice::from_ref(byte))?);
+        }
+        assert_eq!(texts(&responses), ["你好", ", Reine"]);
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_stream_split_in_chunks() -> Result<()> {
+        for size in [2, 3, 7, 16, 50] {
+            let mut buffer = Vec::new();
+            let mut responses = Vec::new();
+            for chunk in STREAM.as_bytes().chunks(size) {
+                responses.extend(parse_sse_chunk(&mut buffer, chunk)?);
+            }
+            assert_eq!(texts(&responses), ["你好", ", Reine"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keep_incomplete_event() -> Result<()> {
+        let mut buffer = Vec::new();
+        let data = "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"你好\"}]}}]}";
+        assert!(parse_sse_chunk(&mut buffer, data.as_bytes())?.is_empty());
+        assert!(parse_sse_chunk(&mut buffer, b"\r\n")?.is_empty());
+        // 响应结束时最后一个事件可能没有以空行结尾
+        let responses = parse_sse_chunk(&mut buffer, b"\n\n")?;
+        assert_eq!(texts(&responses), ["你好"]);
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_error_event() -> Result<()> {
+        let stream = concat!(
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"你好\"}],\"role\":\"model\"}}]}\r\n\r\n",
+            "data: {\"error\":{\"code\":500,\"message\":\"An internal error has occurred.\",\"status\":\"INTERNAL\"}}\r\n\r\n",
+        );
+        let mut buffer = Vec::new();
+        // 先返回错误之前的响应片段
+        let responses = parse_sse_chunk(&mut buffer, stream.as_bytes())?;
+        assert_eq!(texts(&responses), ["你好"]);
+        let err = parse_sse_chunk(&mut buffer, b"").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(GeminiError::Api { code: 500, .. })));
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_data() {
+        let mut buffer = Vec::new();
+        assert!(parse_sse_chunk(&mut buffer, b"data: {\"candidates\":\n\n").is_err());
+    }
+}
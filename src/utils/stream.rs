@@ -0,0 +1,241 @@
+use anyhow::{bail, Error, Result};
+use futures_util::{future, stream, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::body::{error::GeminiError, response::GenerateContentResponse};
+
+/// 流式 JSON 累加器
+/// 累加流式响应片段中的文本，在流结束时将完整文本反序列化为指定类型
+#[derive(Clone, Debug, Default)]
+pub struct JsonAccumulator {
+    text: String,
+}
+
+impl JsonAccumulator {
+    /// 创建新实例
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加一个响应片段中第一个候选结果的文本
+    pub fn push(&mut self, chunk: &GenerateContentResponse) {
+        if let Some(candidate) = chunk.candidates.first() {
+            self.text.push_str(&candidate.content.text());
+        }
+    }
+
+    /// 目前已累加的文本
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// 尽力解析目前已累加的不完整 JSON，可在每次 `push` 后调用以预览已生成的字段
+    /// 未闭合的字符串、数组与对象会被补全，无法补全的末尾内容（如未写完的键或字面量）会被丢弃，无法解析时返回 None
+    /// 仅为尽力而为的预览，末尾的字符串、数字在之后的片段中仍可能变化，最终结果以流结束后 `finish` 的严格解析为准
+    pub fn partial(&self) -> Option<Value> {
+        parse_partial_json(&self.text)
+    }
+
+    /// 流结束后将累加的文本反序列化为指定类型
+    pub fn finish<T: DeserializeOwned>(self) -> Result<T> {
+        if self.text.is_empty() {
+            bail!(GeminiError::NoTextPart)
+        }
+        let json = crate::body::response::strip_code_fence(&self.text);
+        serde_json::from_str(json).map_err(|e| {
+            GeminiError::InvalidJson {
+                text: self.text.clone(),
+                message: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// 流中断时返回携带已累加文本的错误，以便调用方决定是否重试
+    pub fn interrupt(self, error: Error) -> Error {
+        // 客户端的流已返回携带文本的中断错误时直接返回
+        if matches!(error.downcast_ref(), Some(GeminiError::StreamInterrupted { .. })) {
+            return error;
+        }
+        GeminiError::StreamInterrupted {
+            partial: self.text,
+            message: error.to_string(),
+        }
+        .into()
+    }
+
+    /// 消费整个流式响应并将完整文本反序列化为指定类型
+    /// 流中断时返回 `GeminiError::StreamInterrupted`，其中包含已接收的文本
+    pub fn collect<T: DeserializeOwned>(
+        stream: impl IntoIterator<Item = Result<GenerateContentResponse>>,
+    ) -> Result<T> {
+        let mut accumulator = Self::new();
+        for chunk in stream {
+            match chunk {
+                Ok(chunk) => accumulator.push(&chunk),
+                Err(e) => return Err(accumulator.interrupt(e)),
+            }
+        }
+        accumulator.finish()
+    }
+
+    /// 异步消费整个流式响应并将完整文本反序列化为指定类型
+    /// 流中断时返回 `GeminiError::StreamInterrupted`，其中包含已接收的文本
+    pub async fn collect_stream<T: DeserializeOwned>(
+        stream: impl Stream<Item = Result<GenerateContentResponse>>,
+    ) -> Result<T> {
+        let mut stream = std::pin::pin!(stream);
+        let mut accumulator = Self::new();
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(chunk) => accumulator.push(&chunk),
+                Err(e) => return Err(accumulator.interrupt(e)),
+            }
+        }
+        accumulator.finish()
+    }
+}
+
+/// 流式文本分句器
+/// 缓存流式文本，在句末标点处切分出完整的句子，可用于边生成边朗读
+/// 中文句末标点（。！？）直接切分，英文句末标点（. ! ?）需后接空白才切分，以免切断小数与缩写
+#[derive(Clone, Debug, Default)]
+pub struct SentenceSplitter {
+    buffer: String,
+}
+
+impl SentenceSplitter {
+    /// 创建新实例
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加一段文本，返回其中已完整的句子
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.buffer.push_str(text);
+        let mut sentences = Vec::new();
+        while let Some(end) = sentence_end(&self.buffer) {
+            let sentence: String = self.buffer.drain(..end).collect();
+            let sentence = sentence.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_owned());
+            }
+        }
+        sentences
+    }
+
+    /// 流结束时返回剩余未以句末标点结尾的文本
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = rest.trim();
+        (!rest.is_empty()).then(|| rest.to_owned())
+    }
+
+    /// 将流式文本转换为逐句产出的流，流结束时产出剩余的文本
+    pub fn split_stream(text: impl Stream<Item = Result<String>>) -> impl Stream<Item = Result<String>> {
+        text.map(Some)
+            .chain(stream::once(future::ready(None)))
+            .scan(Self::new(), |splitter, text| {
+                let sentences: Vec<Result<String>> = match text {
+                    Some(Ok(text)) => splitter.push(&text).into_iter().map(Ok).collect(),
+                    Some(Err(e)) => vec![Err(e)],
+                    None => splitter.finish().into_iter().map(Ok).collect(),
+                };
+                future::ready(Some(stream::iter(sentences)))
+            })
+            .flatten()
+    }
+}
+
+/// 查找缓冲区中第一个完整句子的结束位置，句末标点之后连续的标点与右引号、右括号一并计入该句
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?' | '。' | '！' | '？') {
+            continue;
+        }
+        let mut cjk = !c.is_ascii();
+        while let Some(&(_, next)) = chars.peek() {
+            if !matches!(
+                next,
+                '.' | '!' | '?' | '。' | '！' | '？' | '…' | '"' | '\'' | '”' | '’' | ')' | '）' | '」' | '』'
+            ) {
+                break;
+            }
+            cjk |= !next.is_ascii();
+            chars.next();
+        }
+        match chars.peek() {
+            Some(&(i, next)) if cjk || next.is_whitespace() => return Some(i),
+            Some(_) => continue,
+            // 英文句末标点位于末尾时，等待下一段文本以确定是否为句子结尾
+            None if cjk => return Some(text.len()),
+            None => return None,
+        }
+    }
+    None
+}
+
+/// 宽松解析不完整的 JSON 文本，忽略第一个 `{` 或 `[` 之前的内容（如代码块标记）
+/// 先补全未闭合的字符串与括号后解析，失败时依次退回到之前的逗号或开括号处再补全解析
+fn parse_partial_json(text: &str) -> Option<Value> {
+    let text = &text[text.find(['{', '['])?..];
+    // 未闭合括号对应的闭括号
+    let mut stack = Vec::new();
+    // 可退回的位置以及在该位置需要补全的闭括号
+    let mut cuts = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut end = text.len();
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => {
+                stack.push(if c == '{' { '}' } else { ']' });
+                cuts.push((i + 1, closers(&stack)));
+            }
+            '}' | ']' => {
+                stack.pop();
+                if stack.is_empty() {
+                    end = i + 1;
+                    break;
+                }
+            }
+            ',' => cuts.push((i, closers(&stack))),
+            _ => {}
+        }
+    }
+    let text = &text[..end];
+    if stack.is_empty() {
+        return serde_json::from_str(text).ok();
+    }
+    let mut completed = text.to_owned();
+    if in_string {
+        // 去掉末尾不完整的转义
+        if escaped {
+            completed.pop();
+        }
+        completed.push('"');
+    }
+    completed.push_str(&closers(&stack));
+    serde_json::from_str(&completed).ok().or_else(|| {
+        cuts.iter()
+            .rev()
+            .find_map(|(pos, closers)| serde_json::from_str(&format!("{}{}", &text[..*pos], closers)).ok())
+    })
+}
+
+/// 按从内到外的顺序拼接闭括号
+fn closers(stack: &[char]) -> String {
+    stack.iter().rev().collect()
+}
@@ -1,9 +1,35 @@
+#![cfg(feature = "blocking")]
 #![allow(deprecated)]
-use std::env;
+use std::{
+    collections::VecDeque,
+    env,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
-use gemini_api::model::blocking::Gemini;
+use gemini_api::body::{error::GeminiError, Content, Role};
+use gemini_api::model::{blocking::Gemini, transport::blocking::HttpTransport};
 use gemini_api::param::LanguageModel;
+use reqwest::header::HeaderMap;
+
+/// 按顺序返回固定响应的传输层，并记录收到的请求体
+#[derive(Default)]
+struct MockTransport {
+    responses: Mutex<VecDeque<(u16, String)>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl HttpTransport for MockTransport {
+    fn post(&self, _url: &str, _headers: &HeaderMap, body: String) -> Result<(u16, String)> {
+        self.requests.lock().unwrap().push(body);
+        Ok(self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("no canned response left"))
+    }
+}
 
 #[test]
 fn test_chat_once() {
@@ -108,6 +134,20 @@ fn test_send_simple_message_conversation() {
     println!("{}: {}", req2, resp2.unwrap().0);
 }
 
+#[test]
+fn test_send_simple_message_stream_conversation() -> Result<()> {
+    let key = env::var("GEMINI_KEY");
+    assert!(key.is_ok());
+    let mut client = Gemini::new(key.unwrap(), LanguageModel::Gemini1_5Flash);
+    client.start_chat(Vec::new());
+    for chunk in client.send_simple_message_stream_blocking("写一首关于 Rust 的短诗".into())? {
+        print!("{}", chunk?.candidates[0].content.text());
+    }
+    println!();
+    assert_eq!(client.contents.len(), 2);
+    Ok(())
+}
+
 #[test]
 #[cfg(feature = "image_analysis")]
 fn test_send_image_message() -> Result<()> {
@@ -133,3 +173,79 @@ fn test_send_image_message_network() -> Result<()> {
     println!("{}", resp);
     Ok(())
 }
+
+#[test]
+fn test_send_video_message_invalid_uri() {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let resp = client.send_video_message(
+        "https://example.com/video.mp4".into(),
+        "video/mp4".into(),
+        "总结一下这个视频".into(),
+        None,
+    );
+    assert!(resp.is_err());
+}
+
+#[test]
+fn test_count_tokens_with_system_instruction() -> Result<()> {
+    let key = env::var("GEMINI_KEY");
+    assert!(key.is_ok());
+    let key = key.unwrap();
+    let client = Gemini::new(key.clone(), LanguageModel::Gemini1_5Flash);
+    let mut instructed_client = Gemini::new(key, LanguageModel::Gemini1_5Flash);
+    instructed_client.set_system_instruction("你是一只猫娘，你每次说话都会在句尾加上喵~ ".into());
+    let message: Content = (Role::User, "My Name is Reine".to_owned()).into();
+    let tokens = client.count_tokens(message.clone())?;
+    let instructed_tokens = instructed_client.count_tokens(message)?;
+    assert!(instructed_tokens > tokens);
+    Ok(())
+}
+
+#[test]
+fn test_offline_count_tokens_body() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_system_instruction("你是一只猫娘，你每次说话都会在句尾加上喵~ ".into());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    client.set_transport(MockTransport {
+        responses: Mutex::new(VecDeque::from([(200, r#"{"totalTokens":12}"#.to_owned())])),
+        requests: requests.clone(),
+    });
+    let tokens = client.count_tokens((Role::User, "My Name is Reine".to_owned()).into())?;
+    assert_eq!(tokens, 12);
+    // 计算 token 数的请求同时包含系统指令与模型
+    let body: serde_json::Value = serde_json::from_str(&requests.lock().unwrap()[0])?;
+    let request = &body["generateContentRequest"];
+    assert_eq!(
+        request["systemInstruction"]["parts"][0]["text"],
+        "你是一只猫娘，你每次说话都会在句尾加上喵~ "
+    );
+    assert_eq!(request["model"], "models/gemini-1.5-flash");
+    Ok(())
+}
+
+#[test]
+fn test_mock_transport_history() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport {
+        responses: Mutex::new(VecDeque::from([
+            (
+                200,
+                r#"{"candidates":[{"content":{"parts":[{"text":"Hello Reine"}],"role":"model"},"finishReason":"STOP"}]}"#
+                    .to_owned(),
+            ),
+            (
+                429,
+                r#"{"error":{"code":429,"message":"Resource has been exhausted.","status":"RESOURCE_EXHAUSTED"}}"#
+                    .to_owned(),
+            ),
+        ])),
+           ..Default::default()
+    });
+    client.start_chat(Vec::new());
+    let (text, _) = client.send_simple_message("My Name is Reine".into())?;
+    assert_eq!(text, "Hello Reine");
+    let err = client.send_simple_message("Who am I".into()).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(GeminiError::Api { code: 429, .. })));
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
@@ -1,11 +1,78 @@
 #![allow(deprecated)]
 
-use std::env;
+use std::{
+    collections::VecDeque,
+    env,
+    pin::pin,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
 
 use anyhow::Result;
-use gemini_api::model::Gemini;
-use gemini_api::param::LanguageModel;
-use tokio::time::{sleep, Duration};
+use futures_util::{future::BoxFuture, StreamExt};
+use gemini_api::body::{
+    error::GeminiError,
+    request::{GenerateAnswerRequest, GenerationConfig, GroundingPassage, GroundingPassages},
+    response::FinishReason,
+    Content, Role,
+};
+use gemini_api::model::{compare_models, transport::HttpTransport, Gemini};
+use gemini_api::param::{ExtractStrategy, LanguageModel};
+use reqwest::header::HeaderMap;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+
+/// 按顺序返回固定响应的传输层，并记录收到的请求体
+#[derive(Clone, Default)]
+struct MockTransport {
+    responses: Arc<Mutex<VecDeque<(u16, String)>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockTransport {
+    fn new(responses: &[(u16, &str)]) -> Self {
+        let responses = responses
+            .iter()
+            .map(|&(status, body)| (status, body.to_owned()))
+            .collect();
+        Self {
+            responses: Arc::new(Mutex::new(responses)),
+            ..Default::default()
+        }
+    }
+
+    /// 已收到的请求体
+    fn requests(&self) -> Vec<serde_json::Value> {
+        let requests = self.requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|body| serde_json::from_str(body).unwrap())
+            .collect()
+    }
+}
+
+impl HttpTransport for MockTransport {
+    fn post<'a>(
+        &'a self,
+        _url: &'a str,
+        _headers: &'a HeaderMap,
+        body: String,
+    ) -> BoxFuture<'a, Result<(u16, String)>> {
+        self.requests.lock().unwrap().push(body);
+        let response = self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("no canned response left");
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+const MOCK_REPLY: &str =
+    r#"{"candidates":[{"content":{"parts":[{"text":"Hello Reine"}],"role":"model"},"finishReason":"STOP"}]}"#;
+const MOCK_ERROR: &str = r#"{"error":{"code":503,"message":"The model is overloaded.","status":"UNAVAILABLE"}}"#;
 
 #[tokio::test]
 async fn test_chat_once() -> Result<()> {
@@ -122,6 +189,27 @@ async fn test_send_simple_message_conversation() {
     println!("{}: {}", req2, resp2.unwrap().0);
 }
 
+#[tokio::test]
+async fn test_send_simple_message_stream_text() -> Result<()> {
+    sleep(Duration::from_secs(60)).await;
+    let key = env::var("GEMINI_KEY");
+    assert!(key.is_ok());
+    let mut client = Gemini::new(key.unwrap(), LanguageModel::Gemini1_5Flash);
+    client.start_chat(Vec::new());
+    {
+        let stream = client
+            .stream_text((Role::User, "写一首关于 Rust 的短诗".to_owned()).into())
+            .await?;
+        let mut stream = pin!(stream);
+        while let Some(text) = stream.next().await {
+            print!("{}", text?);
+        }
+        println!();
+    }
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
 #[tokio::test]
 #[cfg(feature = "image_analysis")]
 async fn test_send_image_message() -> Result<()> {
@@ -153,3 +241,849 @@ async fn test_send_image_message_network() -> Result<()> {
     println!("{}", resp);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_send_video_message_invalid_uri() {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let resp = client
+        .send_video_message(
+            "https://example.com/video.mp4".into(),
+            "video/mp4".into(),
+            "总结一下这个视频".into(),
+            None,
+        )
+        .await;
+    assert!(resp.is_err());
+}
+
+#[tokio::test]
+async fn test_upload_file_missing() {
+    let client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let mut progress = Vec::new();
+    let resp = client
+        .upload_file("./not_exists.mp4".into(), "video/mp4".into(), |sent, total| {
+            progress.push((sent, total))
+        })
+        .await;
+    assert!(resp.is_err());
+    assert!(progress.is_empty());
+}
+
+#[test]
+fn test_fluent_options() {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash)
+        .temperature(0.2)
+        .top_p(0.9)
+        .top_k(40)
+        .max_output_tokens(1024);
+    assert_eq!(client.options.temperature, Some(0.2));
+    assert_eq!(client.options.top_p, Some(0.9));
+    assert_eq!(client.options.top_k, Some(40));
+    assert_eq!(client.options.max_output_tokens, Some(1024));
+    client.set_options(GenerationConfig::default());
+    let client = client.top_k(64);
+    assert_eq!(client.options.temperature, GenerationConfig::default().temperature);
+    assert_eq!(client.options.top_k, Some(64));
+}
+
+#[test]
+fn test_recent_history() {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.start_chat(vec![
+        (Role::User, "My Name is Reine".to_owned()).into(),
+        (Role::Model, "Hello Reine".to_owned()).into(),
+        (Role::User, "Who am I".to_owned()).into(),
+    ]);
+    assert_eq!(client.history_len(), 3);
+    assert_eq!(client.recent(2)[0].as_role_text().unwrap().1, "Hello Reine");
+    assert_eq!(client.recent(5).len(), 3);
+    assert!(client.recent(0).is_empty());
+}
+
+#[tokio::test]
+async fn test_count_tokens_with_system_instruction() -> Result<()> {
+    sleep(Duration::from_secs(60)).await;
+    let key = env::var("GEMINI_KEY");
+    assert!(key.is_ok());
+    let key = key.unwrap();
+    let client = Gemini::new(key.clone(), LanguageModel::Gemini1_5Flash);
+    let mut instructed_client = Gemini::new(key, LanguageModel::Gemini1_5Flash);
+    instructed_client.set_system_instruction("你是一只猫娘，你每次说话都会在句尾加上喵~ ".into());
+    let message: Content = (Role::User, "My Name is Reine".to_owned()).into();
+    let tokens = client.count_tokens(message.clone()).await?;
+    let instructed_tokens = instructed_client.count_tokens(message).await?;
+    assert!(instructed_tokens > tokens);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offline_count_tokens_body() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_system_instruction("你是一只猫娘，你每次说话都会在句尾加上喵~ ".into());
+    let transport = MockTransport::new(&[(200, r#"{"totalTokens":12}"#)]);
+    client.set_transport(transport.clone());
+    let tokens = client
+        .count_tokens((Role::User, "My Name is Reine".to_owned()).into())
+        .await?;
+    assert_eq!(tokens, 12);
+    // 计算 token 数的请求同时包含系统指令与模型
+    let request = &transport.requests()[0]["generateContentRequest"];
+    assert_eq!(
+        request["systemInstruction"]["parts"][0]["text"],
+        "你是一只猫娘，你每次说话都会在句尾加上喵~ "
+    );
+    assert_eq!(request["model"], "models/gemini-1.5-flash");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_regenerate_without_pending_message() {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    assert!(client.regenerate().await.is_err());
+    client.start_chat(vec![
+        (Role::User, "My Name is Reine".to_owned()).into(),
+        (Role::Model, "Hello Reine".to_owned()).into(),
+    ]);
+    assert!(client.regenerate().await.is_err());
+    assert_eq!(client.history_len(), 2);
+}
+
+#[tokio::test]
+async fn test_batch_embed_contents_cancelled() -> Result<()> {
+    let client = Gemini::new("".into(), LanguageModel::Custom("text-embedding-004".into()));
+    let cancelled = AtomicBool::new(true);
+    let texts = vec!["Reine".to_owned(), "Mochi".to_owned()];
+    let (embeddings, was_cancelled) = client.batch_embed_contents_cancellable(texts, &cancelled).await?;
+    assert!(was_cancelled);
+    assert_eq!(embeddings.len(), 2);
+    assert!(embeddings.iter().all(Option::is_none));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mock_transport_history() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[
+        (200, MOCK_REPLY),
+        (503, MOCK_ERROR),
+        (503, MOCK_ERROR),
+        (200, MOCK_REPLY),
+    ]));
+    client.start_chat(Vec::new());
+    let (text, _) = client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(client.history_len(), 2);
+    // 请求失败时移除最后发送的那次用户请求
+    let err = client.send_simple_message("Who am I".into()).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(GeminiError::Api { code: 503, .. })));
+    assert_eq!(client.history_len(), 2);
+    // 保留失败的请求后重新请求
+    client.set_keep_failed_turns(true);
+    assert!(client.send_simple_message("Who am I".into()).await.is_err());
+    assert_eq!(client.history_len(), 3);
+    client.regenerate().await?;
+    assert_eq!(client.history_len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_non_json_error_body() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(
+        503,
+        "<html><body>Service Unavailable</body></html>",
+    )]));
+    let err = client.send_simple_message("My Name is Reine".into()).await.unwrap_err();
+    // 代理返回的 HTML 错误页同样以 GeminiError::Api 返回，保留状态码
+    let error = err.downcast_ref::<GeminiError>().unwrap();
+    assert!(matches!(error, GeminiError::Api { code: 503, .. }));
+    assert!(error.is_retryable());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_turn_on_any_failure() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[
+        (200, "not json"),
+        (
+            200,
+            r#"{"candidates":[{"content":{"parts":[{"text":" "}],"role":"model"},"finishReason":"STOP"}]}"#,
+        ),
+        (200, MOCK_REPLY),
+    ]));
+    client.start_chat(Vec::new());
+    // 响应无法解析时同样移除用户请求
+    assert!(client
+        .send_message((Role::User, "My Name is Reine".to_owned()).into())
+        .await
+        .is_err());
+    assert_eq!(client.history_len(), 0);
+    // 重试空回复失败时同样移除用户请求
+    client.set_retry_on_empty(0);
+    assert!(client.send_simple_message("My Name is Reine".into()).await.is_err());
+    assert_eq!(client.history_len(), 0);
+    client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "image_analysis")]
+async fn test_keep_failed_image_turn() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(503, MOCK_ERROR), (200, MOCK_REPLY)]));
+    client.start_chat(Vec::new());
+    client.set_keep_failed_turns(true);
+    let image_path = "./file_type_rust.png".to_owned();
+    assert!(client
+        .send_image_message(image_path, "分析一下这张图片".into())
+        .await
+        .is_err());
+    assert_eq!(client.history_len(), 1);
+    client.regenerate().await?;
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_model_prefix_reply() -> Result<()> {
+    const PREFIX_REPLY: &str = r#"{"candidates":[{"content":{"parts":[{"text":"Reine\"}"},{"functionCall":{"name":"greet"}}],"role":"model"},"finishReason":"STOP"}]}"#;
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let transport = MockTransport::new(&[(200, PREFIX_REPLY)]);
+    client.set_transport(transport.clone());
+    client.start_chat(Vec::new());
+    client.set_extract_strategy(ExtractStrategy::FirstText);
+    let (text, _) = client
+        .send_with_model_prefix("My Name is Reine".into(), r#"{"name": ""#.into())
+        .await?;
+    assert_eq!(text, r#"{"name": "Reine"}"#);
+    assert_eq!(
+        transport.requests()[0]["contents"][1]["parts"][0]["text"],
+        r#"{"name": ""#
+    );
+    // 完整的回复内容加入历史记录，不丢弃函数调用
+    assert_eq!(client.history_len(), 2);
+    let (_, model) = client.turns().next().unwrap();
+    let model = model.unwrap();
+    assert_eq!(model.parts.len(), 2);
+    assert_eq!(model.text(), r#"{"name": "Reine"}"#);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_without_parts() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[
+        (
+            200,
+            r#"{"candidates":[{"content":{"parts":[],"role":"model"},"finishReason":"STOP"}]}"#,
+        ),
+        (
+            200,
+            r#"{"candidates":[{"content":{"role":"model"},"finishReason":"STOP"}]}"#,
+        ),
+    ]));
+    assert_eq!(client.chat_once("My Name is Reine".into()).await?, "");
+    let (text, response) = client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(text, "");
+    assert!(matches!(response.candidates[0].finish_reason, Some(FinishReason::Stop)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_simple_message_raw() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(200, MOCK_REPLY), (200, r#"{"candidates":{}}"#)]));
+    client.start_chat(Vec::new());
+    let (text, raw) = client.send_simple_message_raw("My Name is Reine".into()).await?;
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(raw, MOCK_REPLY);
+    // 解析失败时错误中携带原始响应体
+    let err = client.send_simple_message_raw("Who am I".into()).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(GeminiError::InvalidResponse { raw, .. }) if raw == r#"{"candidates":{}}"#
+    ));
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auto_trim_history() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    assert!(client.set_auto_trim_to_model_limit(1.0).await.is_err());
+    client.start_chat(vec![
+        (Role::User, "My Name is Reine".to_owned()).into(),
+        (Role::Model, "Hello Reine".to_owned()).into(),
+        (Role::User, "I have a cat".to_owned()).into(),
+        (Role::Model, "Nice".to_owned()).into(),
+    ]);
+    client.set_auto_trim_tokens(100);
+    client.set_system_instruction("你是 Reine".into());
+    let transport = MockTransport::new(&[
+        (200, r#"{"totalTokens":150}"#),
+        (200, r#"{"totalTokens":70}"#),
+        (200, MOCK_REPLY),
+    ]);
+    client.set_transport(transport.clone());
+    client.send_simple_message("Who am I".into()).await?;
+    // 超过上限时移除最早的一轮对话，整个历史记录只计算一次，之后只计算被移除的那一轮
+    let requests = transport.requests();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(
+        requests[0]["generateContentRequest"]["contents"]
+            .as_array()
+            .unwrap()
+            .len(),
+        5
+    );
+    let turn = &requests[1]["generateContentRequest"];
+    assert_eq!(turn["contents"].as_array().unwrap().len(), 2);
+    assert!(turn["systemInstruction"].is_null());
+    assert_eq!(requests[2]["contents"].as_array().unwrap().len(), 3);
+    assert_eq!(client.history_len(), 4);
+    assert_eq!(client.contents[0].text(), "I have a cat");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_model_version() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Custom("gemini-1.5-flash-002".into()));
+    client.set_transport(MockTransport::new(&[
+        (200, MOCK_REPLY),
+        (
+            200,
+            r#"{"candidates":[{"content":{"parts":[{"text":"Hello Reine"}],"role":"model"},"finishReason":"STOP"}],"modelVersion":"gemini-1.5-flash-002"}"#,
+        ),
+    ]));
+    // API 未返回模型版本时使用请求的模型
+    let (_, response) = client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(response.model_version(), Some("models/gemini-1.5-flash-002"));
+    let (_, response) = client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(response.model_version(), Some("gemini-1.5-flash-002"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_send_function_responses() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let transport = MockTransport::new(&[(200, MOCK_REPLY)]);
+    client.set_transport(transport.clone());
+    client.start_chat(Vec::new());
+    let (text, _) = client
+        .send_function_responses(vec![
+            ("get_weather".into(), serde_json::json!({"weather": "sunny"})),
+            ("get_time".into(), serde_json::json!("12:00")),
+        ])
+        .await?;
+    assert_eq!(text, "Hello Reine");
+    // 所有结果在同一条函数角色的消息中发送
+    let requests = transport.requests();
+    let content = &requests[0]["contents"][0];
+    assert_eq!(content["role"], "function");
+    assert_eq!(
+        content["parts"],
+        serde_json::json!([
+            {"functionResponse": {"name": "get_weather", "response": {"weather": "sunny"}}},
+            {"functionResponse": {"name": "get_time", "response": {"result": "12:00"}}},
+        ])
+    );
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ask() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let transport = MockTransport::new(&[(200, MOCK_REPLY)]);
+    client.set_transport(transport.clone());
+    client.start_chat(vec![(Role::User, "My Name is Reine".to_owned()).into()]);
+    assert_eq!(client.ask("Hello").await?, "Hello Reine");
+    // 不发送也不记录历史记录
+    let requests = transport.requests();
+    assert_eq!(requests[0]["contents"].as_array().unwrap().len(), 1);
+    assert_eq!(client.history_len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_join_separator() -> Result<()> {
+    const SPLIT_REPLY: &str = r#"{"candidates":[{"content":{"parts":[{"text":"Hello"},{"text":"Reine"}],"role":"model"},"finishReason":"STOP"}]}"#;
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(200, SPLIT_REPLY), (200, SPLIT_REPLY)]));
+    assert_eq!(client.ask("Hello").await?, "HelloReine");
+    client.set_text_join_separator("\n".into());
+    assert_eq!(client.ask("Hello").await?, "Hello\nReine");
+    Ok(())
+}
+
+/// 依次以给定的状态码响应图片请求的本地服务器，返回图片地址
+#[cfg(feature = "image_analysis")]
+async fn serve_image(statuses: &'static [u16]) -> Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/a.png", listener.local_addr()?);
+    tokio::spawn(async move {
+        for status in statuses {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await;
+            let body: &[u8] = if *status == 200 { b"\x89PNG\r\n\x1a\n" } else { b"" };
+            let head = format!(
+                "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            let _ = socket.write_all(&[head.as_bytes(), body].concat()).await;
+        }
+    });
+    Ok(url)
+}
+
+#[tokio::test]
+#[cfg(feature = "image_analysis")]
+async fn test_download_history_image_once() -> Result<()> {
+    use gemini_api::body::Part;
+
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let transport = MockTransport::new(&[(200, MOCK_REPLY), (200, MOCK_REPLY)]);
+    client.set_transport(transport.clone());
+    client.start_chat(Vec::new());
+    // 图片服务器只响应一次，之后的请求使用历史记录中已下载的图片
+    let url = serve_image(&[200]).await?;
+    let message = Content {
+        parts: vec![Part::Text("分析一下这张图片".into()), Part::image_url(url)],
+        role: Some(Role::User),
+    };
+    client.send_message(message).await?;
+    assert!(matches!(client.contents[0].parts[1], Part::InlineData { .. }));
+    client.send_simple_message("Who am I".into()).await?;
+    let requests = transport.requests();
+    assert_eq!(
+        requests[1]["contents"][0]["parts"][1]["inline_data"]["mimeType"],
+        "image/png"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "image_analysis")]
+async fn test_auto_trim_image_history() -> Result<()> {
+    use gemini_api::body::Part;
+
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.start_chat(vec![
+        (Role::User, "My Name is Reine".to_owned()).into(),
+        (Role::Model, "Hello Reine".to_owned()).into(),
+    ]);
+    client.set_auto_trim_tokens(100);
+    client.set_transport(MockTransport::new(&[
+        (200, r#"{"totalTokens":400}"#),
+        (200, r#"{"totalTokens":10}"#),
+        (200, MOCK_REPLY),
+    ]));
+    // 图片服务器只响应一次，计算 token 数与发送使用同一份已下载的图片
+    let url = serve_image(&[200]).await?;
+    let message = Content {
+        parts: vec![Part::Text("分析一下这张图片".into()), Part::image_url(url)],
+        role: Some(Role::User),
+    };
+    client.send_message(message).await?;
+    // 至少保留即将发送的消息
+    assert_eq!(client.history_len(), 2);
+    assert!(matches!(client.contents[0].parts[1], Part::InlineData { .. }));
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "image_analysis")]
+async fn test_regenerate_like_send() -> Result<()> {
+    use gemini_api::body::Part;
+
+    const EMPTY_REPLY: &str =
+        r#"{"candidates":[{"content":{"parts":[{"text":" "}],"role":"model"},"finishReason":"STOP"}]}"#;
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[
+        (503, MOCK_ERROR),
+        (200, EMPTY_REPLY),
+        (200, MOCK_REPLY),
+    ]));
+    client.start_chat(Vec::new());
+    client.set_keep_failed_turns(true);
+    // 图片服务器只响应一次，重新请求时使用已下载的图片
+    let url = serve_image(&[200]).await?;
+    let message = Content {
+        parts: vec![Part::Text("分析一下这张图片".into()), Part::image_url(url)],
+        role: Some(Role::User),
+    };
+    assert!(client.send_message(message).await.is_err());
+    assert!(matches!(client.contents[0].parts[1], Part::InlineData { .. }));
+    // 重新请求时同样重试空回复
+    client.set_retry_on_empty(1);
+    let (text, _) = client.regenerate().await?;
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "image_analysis")]
+async fn test_extract_image_reply() -> Result<()> {
+    const SPLIT_REPLY: &str = r#"{"candidates":[{"content":{"parts":[{"functionCall":{"name":"get_weather"}},{"text":"Hello"},{"text":"Reine"}],"role":"model"},"finishReason":"STOP"}]}"#;
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(200, SPLIT_REPLY)]));
+    client.set_text_join_separator(" ".into());
+    let (text, _) = client
+        .send_image_message("./file_type_rust.png".into(), "分析一下这张图片".into())
+        .await?;
+    // 与其他发送方法一致，按提取方式返回文本，第一部分为函数调用时不再返回错误
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(client.last_candidates().map(<[_]>::len), Some(1));
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg(feature = "image_analysis")]
+async fn test_retry_image_download() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(200, MOCK_REPLY)]));
+    client.set_image_download_retries(2);
+    let url = serve_image(&[503, 502, 200]).await?;
+    let (text, _) = client.send_image_message(url, "分析一下这张图片".into()).await?;
+    assert_eq!(text, "Hello Reine");
+    // 不重试时第一次下载失败即返回错误
+    client.set_image_download_retries(0);
+    let url = serve_image(&[503, 200]).await?;
+    assert!(client.send_image_message(url, "分析一下这张图片".into()).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_code_execution_history_round_trip() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(
+        200,
+        r#"{"candidates":[{"content":{"parts":[{"text":"Let me compute it."},{"executableCode":{"language":"PYTHON","code":"print(2 ** 10)"}},{"codeExecutionResult":{"outcome":"OUTCOME_OK","output":"1024\n"}},{"text":"2^10 is 1024."}],"role":"model"},"finishReason":"STOP"}]}"#,
+    )]));
+    client.start_chat(Vec::new());
+    client.send_simple_message("What is 2^10?".into()).await?;
+    // 代码执行的内容原样保存在历史记录中，并可序列化后再次发送
+    let model_turn = serde_json::to_value(&client.contents[1])?;
+    assert_eq!(
+        model_turn["parts"][1],
+        serde_json::json!({"executableCode": {"language": "PYTHON", "code": "print(2 ** 10)"}})
+    );
+    assert_eq!(
+        model_turn["parts"][2],
+        serde_json::json!({"codeExecutionResult": {"outcome": "OUTCOME_OK", "output": "1024\n"}})
+    );
+    let resent: Content = serde_json::from_value(model_turn)?;
+    assert_eq!(resent, client.contents[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cache_system_instruction() -> Result<()> {
+    let mut client = Gemini::with_system_instruction("".into(), LanguageModel::Gemini1_5Flash, "你是 Reine".into());
+    let transport = MockTransport::new(&[
+        (
+            200,
+            r#"{"name":"cachedContents/abc-123","model":"models/gemini-1.5-flash","expireTime":"2024-09-01T00:00:00Z"}"#,
+        ),
+        (200, MOCK_REPLY),
+    ]);
+    client.set_transport(transport.clone());
+    client.cache_system_instruction().await?;
+    client.send_simple_message("Who am I".into()).await?;
+    let requests = transport.requests();
+    assert_eq!(requests[0]["systemInstruction"]["parts"][0]["text"], "你是 Reine");
+    // 之后的请求引用缓存而不再发送系统指令
+    assert_eq!(requests[1]["cachedContent"], "cachedContents/abc-123");
+    assert!(requests[1].get("systemInstruction").is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_keepalive() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash)
+        .with_tcp_keepalive(Duration::from_secs(30))?
+        .with_connect_timeout(Duration::from_secs(5))?;
+    client.set_transport(MockTransport::new(&[(200, MOCK_REPLY)]));
+    let (text, _) = client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(text, "Hello Reine");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_on_empty() -> Result<()> {
+    const EMPTY: &str =
+        r#"{"candidates":[{"content":{"parts":[{"text":" \n"}],"role":"model"},"finishReason":"STOP"}]}"#;
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.start_chat(Vec::new());
+    client.set_retry_on_empty(2);
+    let transport = MockTransport::new(&[(200, EMPTY), (200, MOCK_REPLY)]);
+    client.set_transport(transport.clone());
+    let (text, _) = client.send_simple_message("My Name is Reine".into()).await?;
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(transport.requests().len(), 2);
+    assert_eq!(client.history_len(), 2);
+
+    // 达到重试次数后仍为空时返回错误，且不保留这次请求
+    client.set_transport(MockTransport::new(&[(200, EMPTY), (200, EMPTY), (200, EMPTY)]));
+    let err = client.send_simple_message("Hello again".into()).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(GeminiError::EmptyResponse(2))));
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_key() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[
+        (200, r#"{"totalTokens":1}"#),
+        (
+            400,
+            r#"{"error":{"code":400,"message":"API key not valid. Please pass a valid API key.","status":"INVALID_ARGUMENT","details":[{"@type":"type.googleapis.com/google.rpc.ErrorInfo","reason":"API_KEY_INVALID","domain":"googleapis.com"}]}}"#,
+        ),
+        (503, MOCK_ERROR),
+    ]));
+    client.verify_key().await?;
+    let err = client.verify_key().await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(GeminiError::InvalidKey(_))));
+    // 其他错误原样返回
+    let err = client.verify_key().await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(GeminiError::Api { code: 503, .. })));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mock_transport_stream() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(
+        200,
+        concat!(
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Hello\"}],\"role\":\"model\"}}]}\r\n\r\n",
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\" Reine\"}],\"role\":\"model\"},\"finishReason\":\"STOP\"}]}\r\n\r\n",
+        ),
+    )]));
+    client.start_chat(Vec::new());
+    let mut text = String::new();
+    {
+        let stream = client
+            .stream_text((Role::User, "My Name is Reine".to_owned()).into())
+            .await?;
+        let mut stream = pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            text.push_str(&chunk?);
+        }
+    }
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_function_call_history() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(
+        200,
+        concat!(
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Let me \"}],\"role\":\"model\"}}]}\r\n\r\n",
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"check\"}],\"role\":\"model\"}}]}\r\n\r\n",
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"functionCall\":{\"name\":\"get_weather\",\"args\":{\"city\":\"Tokyo\"}},\"thoughtSignature\":\"c2ln\"}],\"role\":\"model\"},\"finishReason\":\"STOP\"}]}\r\n\r\n",
+        ),
+    )]));
+    client.start_chat(Vec::new());
+    let response = client
+        .send_stream_collect((Role::User, "What's the weather in Tokyo".to_owned()).into(), |_| {})
+        .await?;
+    assert_eq!(response.function_call().map(|(name, _)| name), Some("get_weather"));
+    // 流式回复的全部内容加入历史记录，文本片段合并，函数调用及其思考签名原样保留
+    assert_eq!(client.history_len(), 2);
+    let model = serde_json::to_value(&client.contents[1])?;
+    assert_eq!(
+        model,
+        serde_json::json!({
+            "parts": [
+                {"text": "Let me check"},
+                {"functionCall": {"name": "get_weather", "args": {"city": "Tokyo"}}, "thoughtSignature": "c2ln"},
+            ],
+            "role": "model",
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_error_event() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(
+        200,
+        concat!(
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Hello\"}],\"role\":\"model\"}}]}\r\n\r\n",
+            "data: {\"error\":{\"code\":500,\"message\":\"An internal error has occurred.\",\"status\":\"INTERNAL\"}}\r\n\r\n",
+        ),
+    )]));
+    client.start_chat(Vec::new());
+    {
+        let stream = client
+            .stream_text((Role::User, "My Name is Reine".to_owned()).into())
+            .await?;
+        let mut stream = pin!(stream);
+        assert_eq!(stream.next().await.unwrap()?, "Hello");
+        // 错误事件以 GeminiError::Api 返回，之后流结束
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(GeminiError::Api { code: 500, .. })));
+        assert!(stream.next().await.is_none());
+    }
+    assert_eq!(client.history_len(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_sentences() -> Result<()> {
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    let chunk = |text: &str| {
+        format!(
+            "data: {{\"candidates\":[{{\"content\":{{\"parts\":[{{\"text\":\"{}\"}}],\"role\":\"model\"}}}}]}}\r\n\r\n",
+            text
+        )
+    };
+    let body = ["Hello Reine. Pi is 3.", "14! 你好", "。今天天气", "不错"]
+        .map(chunk)
+        .concat();
+    client.set_transport(MockTransport::new(&[(200, &body)]));
+    let stream = client
+        .stream_sentences((Role::User, "My Name is Reine".to_owned()).into())
+        .await?;
+    let sentences: Vec<String> = stream.map(|sentence| sentence.unwrap()).collect().await;
+    assert_eq!(sentences, ["Hello Reine.", "Pi is 3.14!", "你好。", "今天天气不错"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_to_channel() -> Result<()> {
+    const BODY: &str = concat!(
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Hello\"}],\"role\":\"model\"}}]}\r\n\r\n",
+        "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\" Reine\"}],\"role\":\"model\"},\"finishReason\":\"STOP\"}]}\r\n\r\n",
+    );
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.set_transport(MockTransport::new(&[(200, BODY), (200, BODY)]));
+    client.start_chat(Vec::new());
+    let (tx, mut rx) = mpsc::channel::<Result<String>>(1);
+    let consumer = async move {
+        let mut texts = Vec::new();
+        while let Some(text) = rx.recv().await {
+            texts.push(text.unwrap());
+        }
+        texts
+    };
+    let (sent, texts) = tokio::join!(
+        client.send_stream_to_channel((Role::User, "My Name is Reine".to_owned()).into(), tx),
+        consumer
+    );
+    sent?;
+    assert_eq!(texts, ["Hello", " Reine"]);
+    assert_eq!(client.history_len(), 2);
+
+    // 接收端关闭时停止接收，不保存这次请求
+    let (tx, rx) = mpsc::channel(1);
+    drop(rx);
+    let sent = client
+        .send_stream_to_channel((Role::User, "Hello again".to_owned()).into(), tx)
+        .await;
+    assert!(sent.is_err());
+    assert_eq!(client.history_len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_auto_continue() -> Result<()> {
+    const TRUNCATED: &str = "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Hello\"}],\"role\":\"model\"},\"finishReason\":\"MAX_TOKENS\"}]}\r\n\r\n";
+    const REST: &str = "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\" Reine\"}],\"role\":\"model\"},\"finishReason\":\"STOP\"}]}\r\n\r\n";
+    let mut client = Gemini::new("".into(), LanguageModel::Gemini1_5Flash);
+    client.start_chat(Vec::new());
+    // 默认不续写，通过结束原因区分被截断的回复
+    client.set_transport(MockTransport::new(&[(200, TRUNCATED)]));
+    let response = client
+        .send_stream_collect((Role::User, "My Name is Reine".to_owned()).into(), |_| {})
+        .await?;
+    assert!(response.is_truncated());
+    assert!(matches!(response.finish_reason(), Some(FinishReason::MaxTokens)));
+    assert_eq!(client.history_len(), 2);
+
+    client.start_chat(Vec::new());
+    client.set_stream_auto_continue(1);
+    let transport = MockTransport::new(&[(200, TRUNCATED), (200, REST)]);
+    client.set_transport(transport.clone());
+    let mut text = String::new();
+    let response = client
+        .send_stream_collect((Role::User, "My Name is Reine".to_owned()).into(), |chunk| {
+            text.push_str(chunk)
+        })
+        .await?;
+    assert_eq!(text, "Hello Reine");
+    assert_eq!(response.candidates[0].content.text(), "Hello Reine");
+    assert!(!response.is_truncated());
+    // 续写请求附带已接收的文本，历史记录只保存拼接后的完整回复
+    let requests = transport.requests();
+    assert_eq!(requests[1]["contents"][1]["parts"][0]["text"], "Hello");
+    assert_eq!(client.history_len(), 2);
+    assert_eq!(client.contents[1].text(), "Hello Reine");
+    Ok(())
+}
+
+#[test]
+fn test_allowed_models() -> Result<()> {
+    let allowed = vec![LanguageModel::Gemini1_5Flash];
+    assert!(Gemini::with_allowed_models("".into(), LanguageModel::Gemini1_5Pro, allowed.clone()).is_err());
+    let mut client = Gemini::with_allowed_models("".into(), LanguageModel::Gemini1_5Flash, allowed)?;
+    assert!(client.set_model(LanguageModel::Gemini1_5Pro).is_err());
+    assert_eq!(client.model, LanguageModel::Gemini1_5Flash);
+    client.set_model(LanguageModel::Custom("models/gemini-1.5-flash".into()))?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_generate_answer() -> Result<()> {
+    sleep(Duration::from_secs(60)).await;
+    let key = env::var("GEMINI_KEY");
+    assert!(key.is_ok());
+    let client = Gemini::new(key.unwrap(), LanguageModel::Custom("models/aqa".into()));
+    let body = GenerateAnswerRequest {
+        contents: vec![(Role::User, "What is the name of the cat?".to_owned()).into()],
+        inline_passages: Some(GroundingPassages {
+            passages: vec![GroundingPassage {
+                id: "cat".into(),
+                content: (Role::User, "Reine has a cat named Mochi.".to_owned()).into(),
+            }],
+        }),
+        ..Default::default()
+    };
+    let response = client.generate_answer(body).await?;
+    assert!(!response.answer.content.text().is_empty());
+    println!("{}", response.answer.content.text());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compare_models() -> Result<()> {
+    sleep(Duration::from_secs(60)).await;
+    let key = env::var("GEMINI_KEY");
+    assert!(key.is_ok());
+    let (text_a, text_b) = compare_models(
+        key.unwrap(),
+        "用一句话介绍 Rust".into(),
+        LanguageModel::Gemini1_5Flash,
+        LanguageModel::Gemini1_5Pro,
+    )
+    .await?;
+    assert!(!text_a.is_empty());
+    assert!(!text_b.is_empty());
+    println!("{}\n{}", text_a, text_b);
+    Ok(())
+}